@@ -0,0 +1,178 @@
+// Copyright 2023 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::errors::Error;
+use crate::interrupt::InterruptHandle;
+use crate::CommandContext;
+use crate::EvalOutputs;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Waker;
+
+/// Wraps a `CommandContext` for use from async code. Evaluations are run one at a time, in the
+/// order in which they were requested, on a dedicated thread. Doesn't depend on any particular async
+/// runtime.
+#[derive(Clone)]
+pub struct AsyncCommandContext {
+    context: Arc<Mutex<CommandContext>>,
+    interrupt_handle: InterruptHandle,
+    job_sender: crossbeam_channel::Sender<Job>,
+}
+
+struct Job {
+    code: String,
+    shared: Arc<Mutex<EvaluationState>>,
+}
+
+impl AsyncCommandContext {
+    pub fn new(context: CommandContext) -> AsyncCommandContext {
+        let interrupt_handle = context.interrupt_handle();
+        let context = Arc::new(Mutex::new(context));
+        let (job_sender, job_receiver) = crossbeam_channel::unbounded();
+        std::thread::spawn({
+            let context = Arc::clone(&context);
+            let interrupt_handle = interrupt_handle.clone();
+            // Terminates once all senders have been dropped.
+            move || {
+                while let Ok(job) = job_receiver.recv() {
+                    run_job(job, &context, &interrupt_handle);
+                }
+            }
+        });
+        AsyncCommandContext {
+            context,
+            interrupt_handle,
+            job_sender,
+        }
+    }
+
+    /// Returns the wrapped context. Locking it will block until any in-progress evaluation
+    /// completes.
+    pub fn context(&self) -> &Arc<Mutex<CommandContext>> {
+        &self.context
+    }
+
+    /// Starts evaluating `code`. The returned future resolves once evaluation completes. Either
+    /// dropping the future before it resolves or calling `cancel` on the returned handle interrupts
+    /// the evaluation, in which case it fails with `Error::Cancelled`. The context remains usable
+    /// afterwards, although if user code was running at the time, variables will have been lost.
+    pub fn execute(&self, code: &str) -> (Evaluation, CancelHandle) {
+        let shared = Arc::new(Mutex::new(EvaluationState::default()));
+        let cancel_handle = CancelHandle {
+            shared: Arc::clone(&shared),
+            interrupt_handle: self.interrupt_handle.clone(),
+        };
+        let job = Job {
+            code: code.to_owned(),
+            shared,
+        };
+        if let Err(crossbeam_channel::SendError(job)) = self.job_sender.send(job) {
+            // Our worker thread has gone away, which can only happen if it panicked.
+            complete(
+                &job.shared,
+                Err(Error::Message("Evaluation thread died".to_owned())),
+            );
+        }
+        (
+            Evaluation {
+                cancel_handle: cancel_handle.clone(),
+            },
+            cancel_handle,
+        )
+    }
+}
+
+fn run_job(job: Job, context: &Mutex<CommandContext>, interrupt_handle: &InterruptHandle) {
+    let mut context = context.lock().unwrap();
+    // Our guard needs to be in place before we mark ourselves as running, otherwise a cancellation
+    // that arrived in between would be ignored.
+    let guard = interrupt_handle.begin_evaluation();
+    let cancelled = {
+        let mut state = job.shared.lock().unwrap();
+        state.running = !state.cancelled;
+        state.cancelled
+    };
+    let result = if cancelled {
+        Err(Error::Cancelled)
+    } else {
+        context.execute(&job.code)
+    };
+    drop(guard);
+    drop(context);
+    complete(&job.shared, result);
+}
+
+fn complete(shared: &Mutex<EvaluationState>, result: Result<EvalOutputs, Error>) {
+    let mut state = shared.lock().unwrap();
+    state.running = false;
+    state.completed = true;
+    state.result = Some(result);
+    if let Some(waker) = state.waker.take() {
+        waker.wake();
+    }
+}
+
+#[derive(Default)]
+struct EvaluationState {
+    cancelled: bool,
+    running: bool,
+    completed: bool,
+    result: Option<Result<EvalOutputs, Error>>,
+    waker: Option<Waker>,
+}
+
+/// Can be used to cancel an evaluation started by `AsyncCommandContext::execute`.
+#[derive(Clone)]
+pub struct CancelHandle {
+    shared: Arc<Mutex<EvaluationState>>,
+    interrupt_handle: InterruptHandle,
+}
+
+impl CancelHandle {
+    /// Cancels the evaluation. Has no effect if the evaluation has already completed.
+    pub fn cancel(&self) {
+        let mut state = self.shared.lock().unwrap();
+        if state.completed || state.cancelled {
+            return;
+        }
+        state.cancelled = true;
+        if state.running {
+            // We hold our lock while interrupting so that we can't interrupt some subsequent
+            // evaluation.
+            self.interrupt_handle.interrupt();
+        }
+    }
+}
+
+/// A future that resolves to the result of an evaluation. See `AsyncCommandContext::execute`.
+pub struct Evaluation {
+    cancel_handle: CancelHandle,
+}
+
+impl Future for Evaluation {
+    type Output = Result<EvalOutputs, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.cancel_handle.shared.lock().unwrap();
+        if let Some(result) = state.result.take() {
+            Poll::Ready(result)
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Evaluation {
+    fn drop(&mut self) {
+        self.cancel_handle.cancel();
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright 2023 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Generation of the code that `:bench` evaluates in order to benchmark an expression.
+
+/// Code that benchmarks EVCXR_BENCH_EXPR. It first warms up, doubling the number of iterations
+/// until it has run for long enough, which also gives an estimate of the time per iteration. That
+/// estimate is used to pick the number of iterations in each sample, such that all the samples
+/// together take about as long as the measurement time. Results are printed as content, the same
+/// way displayed values are, so the block ends with a semicolon to avoid also displaying `()`.
+///
+/// The expression is put in a closure before anything else and everything else is in an inner
+/// block, so that the names we define can't shadow names used by the expression.
+const BENCH_CODE: &str = r#"{
+    let mut evcxr_bench_expr = || (EVCXR_BENCH_EXPR);
+    {
+        use std::time::Duration;
+        use std::time::Instant;
+        const WARM_UP_TIME: Duration = Duration::from_millis(300);
+        const MEASUREMENT_TIME: Duration = Duration::from_secs(2);
+        const MIN_SAMPLES: u64 = 10;
+        const MAX_SAMPLES: u64 = 50;
+        fn format_duration(ns: f64) -> String {
+            if ns < 1e3 {
+                format!("{:.2} ns", ns)
+            } else if ns < 1e6 {
+                format!("{:.2} µs", ns / 1e3)
+            } else if ns < 1e9 {
+                format!("{:.2} ms", ns / 1e6)
+            } else {
+                format!("{:.2} s", ns / 1e9)
+            }
+        }
+        fn format_throughput(per_second: f64) -> String {
+            if per_second >= 1e9 {
+                format!("{:.2} G iter/s", per_second / 1e9)
+            } else if per_second >= 1e6 {
+                format!("{:.2} M iter/s", per_second / 1e6)
+            } else if per_second >= 1e3 {
+                format!("{:.2} K iter/s", per_second / 1e3)
+            } else {
+                format!("{:.2} iter/s", per_second)
+            }
+        }
+        let mut run = |iterations: u64| -> Duration {
+            let start = Instant::now();
+            for _ in 0..iterations {
+                let _ = std::hint::black_box(evcxr_bench_expr());
+            }
+            start.elapsed()
+        };
+        let mut iterations = 1;
+        let warm_up_start = Instant::now();
+        let mut elapsed = run(iterations);
+        while warm_up_start.elapsed() < WARM_UP_TIME {
+            iterations *= 2;
+            elapsed = run(iterations);
+        }
+        let estimated_ns = (elapsed.as_nanos() as f64 / iterations as f64).max(1.0);
+        let total_iterations = (MEASUREMENT_TIME.as_nanos() as f64 / estimated_ns) as u64;
+        let samples = total_iterations.clamp(MIN_SAMPLES, MAX_SAMPLES);
+        let iterations = (total_iterations / samples).max(1);
+        let mut times: Vec<f64> = (0..samples)
+            .map(|_| run(iterations).as_nanos() as f64 / iterations as f64)
+            .collect();
+        times.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let count = times.len() as f64;
+        let mean = times.iter().sum::<f64>() / count;
+        let middle = times.len() / 2;
+        let median = if times.len() % 2 == 0 {
+            (times[middle - 1] + times[middle]) / 2.0
+        } else {
+            times[middle]
+        };
+        let variance = times.iter().map(|t| (t - mean).powi(2)).sum::<f64>() / (count - 1.0);
+        let std_dev = variance.sqrt();
+        let rows = [
+            ("mean", format_duration(mean)),
+            ("median", format_duration(median)),
+            ("std dev", format_duration(std_dev)),
+            ("throughput", format_throughput(1e9 / mean)),
+        ];
+        let description = format!(
+            "{} samples of {} iterations at opt-level EVCXR_BENCH_OPT_LEVEL",
+            samples, iterations
+        );
+        println!("EVCXR_BEGIN_CONTENT text/plain");
+        for (name, value) in &rows {
+            println!("{:<11} {}", format!("{}:", name), value);
+        }
+        println!("{}\nEVCXR_END_CONTENT", description);
+        println!("EVCXR_BEGIN_CONTENT text/html");
+        print!("<table>");
+        for (name, value) in &rows {
+            print!("<tr><th>{}</th><td>{}</td></tr>", name, value);
+        }
+        println!("</table><p>{}</p>\nEVCXR_END_CONTENT", description);
+    }
+};"#;
+
+/// Returns code that benchmarks `expr`, reporting that it was compiled at `opt_level`.
+pub(crate) fn benchmark_code(expr: &str, opt_level: &str) -> String {
+    BENCH_CODE
+        .replace("EVCXR_BENCH_OPT_LEVEL", opt_level)
+        .replace("EVCXR_BENCH_EXPR", expr)
+}
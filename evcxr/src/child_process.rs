@@ -111,6 +111,11 @@ impl ChildProcess {
         self.process_handle.clone()
     }
 
+    /// Returns the ID of the current subprocess.
+    pub(crate) fn pid(&self) -> u32 {
+        self.process_handle.lock().unwrap().id()
+    }
+
     /// Terminates this process if it hasn't already, then restarts
     pub(crate) fn restart(&mut self) -> Result<ChildProcess, Error> {
         // If the process hasn't already terminated for some reason, kill it.
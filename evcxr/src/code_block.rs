@@ -51,6 +51,10 @@ pub(crate) struct CommandCall {
     pub(crate) line_number: usize,
 }
 
+/// Commands that take the remainder of the cell as their arguments, so that it can contain code in
+/// another language.
+const CELL_COMMANDS: &[&str] = &[":c", ":cpp"];
+
 #[derive(Clone, PartialEq, Eq, Debug)]
 pub(crate) enum CodeKind {
     /// The code was supplied by the user. Errors should be reported to the user.
@@ -194,15 +198,29 @@ impl CodeBlock {
         for (command_line_offset, line) in user_code.lines().enumerate() {
             // We only accept commands up until the first non-command.
             if let Some(captures) = command_re.captures(line) {
+                let start_byte = line.as_ptr() as usize - user_code.as_ptr() as usize;
+                let is_cell_command = CELL_COMMANDS.contains(&&captures[1]);
+                let (code, args) = if is_cell_command {
+                    let args_start = start_byte + captures.get(1).map_or(0, |m| m.end());
+                    (
+                        &user_code[start_byte..],
+                        Some(user_code[args_start..].trim_start_matches(' ').to_owned()),
+                    )
+                } else {
+                    (line, captures.get(3).map(|m| m.as_str().to_owned()))
+                };
                 code_block = code_block.with(
                     CodeKind::Command(CommandCall {
                         command: captures[1].to_owned(),
-                        args: captures.get(3).map(|m| m.as_str().to_owned()),
-                        start_byte: line.as_ptr() as usize - user_code.as_ptr() as usize,
+                        args,
+                        start_byte,
                         line_number: command_line_offset + 1,
                     }),
-                    line,
+                    code,
                 );
+                if is_cell_command {
+                    break;
+                }
             } else if line.starts_with(r"//") || line.trim().is_empty() {
                 // Ignore blank lines, otherwise we can't have blank lines before :dep commands.
                 // We also ignore lines that start with //, because those are line comments.
@@ -6,36 +6,55 @@
 // copied, modified, or distributed except according to those terms.
 
 use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
 
+use crate::bench;
 use crate::code_block::CodeBlock;
 use crate::code_block::CodeKind;
 use crate::code_block::CommandCall;
 use crate::code_block::Segment;
 use crate::code_block::{self};
 use crate::crash_guard::CrashGuard;
+use crate::emit;
+use crate::emit::EmitKind;
+use crate::errors;
 use crate::errors::bail;
+use crate::errors::html_escape;
 use crate::errors::CompilationError;
 use crate::errors::Error;
 use crate::errors::Span;
 use crate::errors::SpannedMessage;
+use crate::errors::Theme;
 use crate::eval_context::ContextState;
 use crate::eval_context::EvalCallbacks;
+use crate::eval_context::Variable;
+use crate::interrupt::InterruptHandle;
+use crate::module::CheckCommand;
 use crate::rust_analyzer::Completion;
 use crate::rust_analyzer::Completions;
+use crate::rustfmt;
+use crate::test_runner;
 use crate::EvalContext;
 use crate::EvalContextOutputs;
 use crate::EvalOutputs;
 use anyhow::Result;
+use ariadne::sources;
 use once_cell::sync::OnceCell;
 
 /// A higher level interface to EvalContext. A bit closer to a Repl. Provides commands (start with
 /// ':') that alter context state or print information.
 pub struct CommandContext {
     print_timings: bool,
+    print_memory: bool,
     eval_context: EvalContext,
     last_errors: Vec<CompilationError>,
+    /// The cell that produced `last_errors`.
+    last_errors_source: String,
+    /// The last cell that contained code other than commands.
+    last_user_code: String,
 }
 
 impl CommandContext {
@@ -48,8 +67,11 @@ impl CommandContext {
     pub fn with_eval_context(eval_context: EvalContext) -> CommandContext {
         CommandContext {
             print_timings: false,
+            print_memory: false,
             eval_context,
             last_errors: Vec::new(),
+            last_errors_source: String::new(),
+            last_user_code: String::new(),
         }
     }
 
@@ -64,6 +86,24 @@ impl CommandContext {
     }
 
     pub fn check(&mut self, code: &str) -> Result<Vec<CompilationError>, Error> {
+        self.check_with(code, CheckCommand::Check)
+    }
+
+    /// Like `check`, but runs Clippy, returning only lints and errors in the supplied code.
+    pub fn clippy(&mut self, code: &str) -> Result<Vec<CompilationError>, Error> {
+        self.check_with(code, CheckCommand::Clippy)
+    }
+
+    /// Formats the Rust code in `code` with rustfmt, leaving any commands as they are.
+    pub fn rustfmt(&self, code: &str) -> Result<String, Error> {
+        rustfmt::format_cell(&self.eval_context.rustfmt_path(), code)
+    }
+
+    fn check_with(
+        &mut self,
+        code: &str,
+        command: CheckCommand,
+    ) -> Result<Vec<CompilationError>, Error> {
         let (user_code, code_info) = CodeBlock::from_original_user_code(code);
         let (non_command_code, state, errors) = self.prepare_for_analysis(user_code)?;
         if !errors.is_empty() {
@@ -72,17 +112,64 @@ impl CommandContext {
             // would be confusing.
             return Ok(errors);
         }
-        self.eval_context.check(non_command_code, state, &code_info)
+        self.eval_context
+            .check(non_command_code, state, &code_info, command)
     }
 
     pub fn process_handle(&self) -> Arc<Mutex<std::process::Child>> {
         self.eval_context.process_handle()
     }
 
+    /// Returns a handle that can be used to interrupt evaluation, including compilation, from
+    /// another thread.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.eval_context.interrupt_handle()
+    }
+
+    /// Restores the context to how it was when it was created, including resetting config. The
+    /// subprocess is restarted. The compilation directory is kept, so dependencies that get added
+    /// again don't need to be rebuilt from scratch.
+    pub fn restart(&mut self) -> Result<(), Error> {
+        self.print_timings = false;
+        self.print_memory = false;
+        self.last_errors.clear();
+        self.last_errors_source.clear();
+        self.last_user_code.clear();
+        self.eval_context.reset_config();
+        self.eval_context.clear()
+    }
+
+    /// See `EvalContext::handle_comm_message`.
+    pub fn handle_comm_message(&mut self, message: &str) -> Result<EvalOutputs, Error> {
+        self.eval_context.handle_comm_message(message)
+    }
+
+    pub(crate) fn tmpdir(&self) -> &Path {
+        self.eval_context.last_compile_dir()
+    }
+
     pub fn variables_and_types(&self) -> impl Iterator<Item = (&str, &str)> {
         self.eval_context.variables_and_types()
     }
 
+    /// See `EvalContext::variables`.
+    pub fn variables(&self) -> Vec<Variable> {
+        self.eval_context.variables()
+    }
+
+    /// See `EvalContext::variable_previews`.
+    pub fn variable_previews(
+        &mut self,
+        max_preview_chars: usize,
+    ) -> Result<HashMap<String, String>, Error> {
+        self.eval_context.variable_previews(max_preview_chars)
+    }
+
+    /// See `EvalContext::eval_readonly`.
+    pub fn eval_readonly(&mut self, expression: &str) -> Result<EvalOutputs, Error> {
+        self.eval_context.eval_readonly(expression)
+    }
+
     pub fn reset_config(&mut self) {
         self.eval_context.reset_config();
     }
@@ -139,6 +226,9 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 non_command_code = non_command_code.with_segment(segment);
             }
         }
+        if !non_command_code.is_empty() {
+            self.last_user_code = to_run.to_owned();
+        }
         let result =
             self.eval_context
                 .eval_with_callbacks(non_command_code, state, &code_info, callbacks);
@@ -149,10 +239,14 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 if self.print_timings {
                     eval_outputs.timing = Some(duration);
                 }
+                if !self.print_memory {
+                    eval_outputs.memory = None;
+                }
                 Ok(eval_outputs)
             }
             Err(Error::CompilationErrors(errors)) => {
                 self.last_errors = errors.clone();
+                self.last_errors_source = to_run.to_owned();
                 Err(Error::CompilationErrors(errors))
             }
             x => x,
@@ -281,8 +375,9 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 // arguments are found, span the command. We look for the first non-space character
                 // after a space is found.
                 let mut found_space = false;
-                let start_byte = segment
-                    .code
+                // Commands such as :c span multiple lines, but we only report errors on the first.
+                let command_line = segment.code.lines().next().unwrap_or_default();
+                let start_byte = command_line
                     .bytes()
                     .enumerate()
                     .find(|(_index, byte)| {
@@ -294,8 +389,8 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     })
                     .map(|(index, _char)| index)
                     .unwrap_or(0);
-                let start_column = code_block::count_columns(&segment.code[..start_byte]) + 1;
-                let end_column = code_block::count_columns(&segment.code);
+                let start_column = code_block::count_columns(&command_line[..start_byte]) + 1;
+                let end_column = code_block::count_columns(command_line);
                 CompilationError::from_segment_span(
                     segment,
                     SpannedMessage::from_segment_span(
@@ -451,12 +546,22 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     ))
                 },
             ),
+            AvailableCommand::new(
+                ":warnings",
+                "Set/print whether compiler warnings in user code are shown (on/off/deny)",
+                |_ctx, state, args| {
+                    if let Some(mode) = args {
+                        state.set_warnings(mode.trim())?;
+                    }
+                    text_output(format!("Warnings: {}", state.warnings()))
+                },
+            ),
             AvailableCommand::new(
                 ":toolchain",
                 "Set which toolchain to use (e.g. nightly)",
                 |_ctx, state, args| {
                     if let Some(arg) = args {
-                        state.set_toolchain(arg);
+                        state.set_toolchain(arg.trim())?;
                     }
                     text_output(format!("Toolchain: {}", state.toolchain()))
                 },
@@ -483,6 +588,14 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     text_output(format!("Timing: {}", ctx.print_timings))
                 },
             ),
+            AvailableCommand::new(
+                ":memory",
+                "Toggle printing of how evaluations affect memory usage (Linux only)",
+                |ctx, _state, _args| {
+                    ctx.print_memory = !ctx.print_memory;
+                    text_output(format!("Memory: {}", ctx.print_memory))
+                },
+            ),
             AvailableCommand::new(
                 ":time_passes",
                 "Toggle printing of rustc pass times (requires nightly)",
@@ -510,24 +623,173 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 },
             ),
             AvailableCommand::new(
-                ":explain",
-                "Print explanation of last error",
+                ":link",
+                "Link against a native library, optionally searching the specified directory. \
+                 e.g. :link foo /path/to/libs",
+                |_ctx, state, args| {
+                    let Some(args) = args else {
+                        let libs: Vec<String> = state
+                            .native_libs()
+                            .iter()
+                            .map(|lib| match &lib.search_path {
+                                Some(path) => format!("{} ({})", lib.name, path.display()),
+                                None => lib.name.clone(),
+                            })
+                            .collect();
+                        return text_output(format!("Native libraries: {}", libs.join(", ")));
+                    };
+                    let mut args = args.splitn(2, ' ');
+                    let name = args.next().unwrap_or_default();
+                    let search_path = args.next().map(str::trim).map(Path::new);
+                    state.add_native_lib(name, search_path)?;
+                    Ok(EvalOutputs::new())
+                },
+            ),
+            AvailableCommand::new(
+                ":c",
+                "Compile the rest of the cell as C. e.g. :c [name] followed by C code",
+                |_ctx, state, args| process_c_command(state, args, "c"),
+            ),
+            AvailableCommand::new(
+                ":cpp",
+                "Compile the rest of the cell as C++. e.g. :cpp [name] followed by C++ code",
+                |_ctx, state, args| process_c_command(state, args, "cpp"),
+            ),
+            AvailableCommand::new(
+                ":build_rs",
+                "Set/print the build script used when compiling",
+                |_ctx, state, args| {
+                    if let Some(path) = args {
+                        state.set_build_rs(Path::new(path))?;
+                    }
+                    text_output(format!(
+                        "Build script: {}",
+                        state
+                            .build_rs()
+                            .map_or("none".into(), |path| path.to_string_lossy())
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":asm",
+                "Print the assembly for a function at the current optimization level. e.g. :asm foo",
+                |ctx, state, args| ctx.emitted_code(state, args, EmitKind::Asm),
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":llvm_ir",
+                "Print the LLVM IR for a function at the current optimization level. \
+                 e.g. :llvm_ir foo",
+                |ctx, state, args| ctx.emitted_code(state, args, EmitKind::LlvmIr),
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":clippy",
+                "Run Clippy on the code from the last cell",
+                |ctx, _state, _args| ctx.clippy_last_cell(),
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":rustfmt",
+                "Format the code from the last cell with rustfmt",
                 |ctx, _state, _args| {
-                    if ctx.last_errors.is_empty() {
-                        bail!("No last error to explain");
-                    } else {
-                        let mut all_explanations = String::new();
-                        for error in &ctx.last_errors {
-                            if let Some(explanation) = error.explanation() {
-                                all_explanations.push_str(explanation);
-                            } else {
-                                bail!("Sorry, last error has no explanation");
-                            }
+                    if ctx.last_user_code.is_empty() {
+                        bail!("No code has been evaluated yet");
+                    }
+                    let formatted = ctx.rustfmt(&ctx.last_user_code)?;
+                    let mut outputs = text_output(&formatted)?;
+                    outputs.next_input = Some(formatted);
+                    Ok(outputs)
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":test",
+                "Run the #[test] functions defined so far, optionally only those whose names \
+                 contain a filter. e.g. :test parse",
+                |ctx, state, args| {
+                    let filter = args.as_deref().map(str::trim).filter(|f| !f.is_empty());
+                    let results = ctx.eval_context.run_tests(state, filter)?;
+                    if results.is_empty() {
+                        return text_output("No tests found");
+                    }
+                    Ok(EvalOutputs::text_html(
+                        test_runner::to_text(&results),
+                        test_runner::to_html(&results),
+                    ))
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":bench",
+                "Benchmark an expression at the current optimization level. \
+                 e.g. :bench v.iter().sum::<i32>()",
+                |ctx, state, args| {
+                    let Some(expr) = args.as_deref().map(str::trim).filter(|e| !e.is_empty())
+                    else {
+                        bail!("Please specify an expression to benchmark");
+                    };
+                    let code = bench::benchmark_code(expr, state.opt_level());
+                    let result = ctx.eval_context.eval_with_state(&code, state.clone());
+                    // Evaluation commits the state, which includes an updated build number.
+                    *state = ctx.eval_context.state();
+                    match result {
+                        // Error spans would refer to the generated benchmark code, which the user
+                        // can't see, so we just report the messages.
+                        Err(Error::CompilationErrors(errors)) => bail!(
+                            "{}",
+                            errors
+                                .iter()
+                                .filter(|error| error.level() == "error")
+                                .map(|error| error.message())
+                                .collect::<Vec<_>>()
+                                .join("\n")
+                        ),
+                        result => result,
+                    }
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":expand",
+                "Print the code from the last cell after macro expansion (requires nightly)",
+                |ctx, state, _args| {
+                    if ctx.last_user_code.is_empty() {
+                        bail!("No code has been evaluated yet");
+                    }
+                    let (cell, _) = CodeBlock::from_original_user_code(&ctx.last_user_code);
+                    let mut user_code = CodeBlock::new();
+                    for segment in cell.segments {
+                        if !matches!(segment.kind, CodeKind::Command(_)) {
+                            user_code = user_code.with_segment(segment);
                         }
-                        text_output(all_explanations)
                     }
+                    text_output(ctx.eval_context.expanded_code(state, user_code)?.trim_end())
                 },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":mir",
+                "Print the MIR for a function (requires nightly). e.g. :mir foo",
+                |ctx, state, args| {
+                    let Some(function) = args.as_deref().map(str::trim) else {
+                        bail!("Please specify the name of a function");
+                    };
+                    text_output(ctx.eval_context.mir(state, function)?.trim_end())
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":explain",
+                "Print explanation of last error, or of an error code. e.g. :explain E0382",
+                |ctx, _state, args| ctx.explain(args),
             ),
+            AvailableCommand::new(
+                ":fix",
+                "Apply the compiler's suggested fixes for the last error and print the fixed code",
+                |ctx, _state, _args| ctx.fix_last_errors(),
+            )
+            .disable_in_analysis(),
             AvailableCommand::new(
                 ":last_error_json",
                 "Print the last compilation error as JSON (for debugging)",
@@ -562,6 +824,109 @@ Panic detected. Here's some useful information if you're filing a bug report.
         ]
     }
 
+    fn fix_last_errors(&mut self) -> Result<EvalOutputs, Error> {
+        if self.last_errors.is_empty() {
+            bail!("No last error to fix");
+        }
+        let suggestions: Vec<_> = self
+            .last_errors
+            .iter()
+            .flat_map(CompilationError::suggestions)
+            .collect();
+        if suggestions.is_empty() {
+            bail!("The compiler didn't suggest any fixes that can be applied automatically");
+        }
+        let fixed = errors::apply_suggestions(&self.last_errors_source, &suggestions);
+        let mut outputs = text_output(&fixed)?;
+        outputs.next_input = Some(fixed);
+        Ok(outputs)
+    }
+
+    fn explain(&self, args: &Option<String>) -> Result<EvalOutputs, Error> {
+        if let Some(code) = args
+            .as_deref()
+            .map(str::trim)
+            .filter(|code| !code.is_empty())
+        {
+            let mut code = code.to_uppercase();
+            if !code.starts_with('E') {
+                code.insert(0, 'E');
+            }
+            return Ok(explanation_outputs(
+                &self.eval_context.explain_error_code(&code)?,
+            ));
+        }
+        if self.last_errors.is_empty() {
+            bail!("No last error to explain");
+        }
+        let mut all_explanations = String::new();
+        let mut explained_codes = HashSet::new();
+        for error in &self.last_errors {
+            if let Some(code) = error.code() {
+                if !explained_codes.insert(code) {
+                    continue;
+                }
+            }
+            // Newer versions of rustc don't include explanations in their JSON output, so we ask
+            // for those that are missing.
+            let explanation = match (error.explanation(), error.code()) {
+                (Some(explanation), _) => explanation.to_owned(),
+                (None, Some(code)) if is_error_code(code) => {
+                    self.eval_context.explain_error_code(code)?
+                }
+                _ => continue,
+            };
+            if !all_explanations.is_empty() {
+                all_explanations.push('\n');
+            }
+            all_explanations.push_str(&explanation);
+        }
+        if all_explanations.is_empty() {
+            bail!("Sorry, last error has no explanation");
+        }
+        Ok(explanation_outputs(&all_explanations))
+    }
+
+    fn clippy_last_cell(&mut self) -> Result<EvalOutputs, Error> {
+        if self.last_user_code.is_empty() {
+            bail!("No code has been evaluated yet");
+        }
+        let source = self.last_user_code.clone();
+        let lints = self.clippy(&source)?;
+        if lints.is_empty() {
+            return text_output("No lints");
+        }
+        let file_name = "command".to_owned();
+        let mut text = String::new();
+        let mut html = String::new();
+        for lint in &lints {
+            html.push_str(&lint.build_html_report(&source));
+            if let Some(report) = lint.build_report(file_name.clone(), source.clone(), Theme::Dark)
+            {
+                let mut out = Vec::new();
+                report.write(sources([(file_name.clone(), source.clone())]), &mut out)?;
+                text.push_str(&String::from_utf8_lossy(&out));
+            } else {
+                text.push_str(&lint.rendered());
+            }
+        }
+        Ok(EvalOutputs::text_html(text, html))
+    }
+
+    fn emitted_code(
+        &mut self,
+        state: &ContextState,
+        args: &Option<String>,
+        kind: EmitKind,
+    ) -> Result<EvalOutputs, Error> {
+        let Some(function) = args.as_deref().map(str::trim) else {
+            bail!("Please specify the name of a function");
+        };
+        let code = self.eval_context.emitted_code(state, kind, function)?;
+        let html = emit::to_html(kind, &code);
+        Ok(EvalOutputs::text_html(code, html))
+    }
+
     fn vars_as_text(&self) -> String {
         let mut out = String::new();
         for (var, ty) in self.eval_context.variables_and_types() {
@@ -609,6 +974,27 @@ fn process_dep_command(
     }
 }
 
+/// Handles :c and :cpp. The first line of `args` is an optional name, which defaults to "default".
+/// The remaining lines are the source, which replaces any previous source with the same name and
+/// extension.
+fn process_c_command(
+    state: &mut ContextState,
+    args: &Option<String>,
+    extension: &str,
+) -> Result<EvalOutputs, Error> {
+    let args = args.as_deref().unwrap_or_default();
+    let (name, source) = args.split_once('\n').unwrap_or((args, ""));
+    let name = match name.trim() {
+        "" => "default",
+        name => name,
+    };
+    if source.trim().is_empty() {
+        bail!(":{extension} should be followed by source code on subsequent lines");
+    }
+    state.set_c_source(name, extension, source)?;
+    Ok(EvalOutputs::new())
+}
+
 type CallbackFn = dyn Fn(&mut CommandContext, &mut ContextState, &Option<String>) -> Result<EvalOutputs, Error>
     + 'static
     + Sync
@@ -663,15 +1049,62 @@ impl AvailableCommand {
     }
 }
 
-fn html_escape(input: &str, out: &mut String) {
-    for ch in input.chars() {
-        match ch {
-            '&' => out.push_str("&amp;"),
-            '<' => out.push_str("&lt;"),
-            '>' => out.push_str("&gt;"),
-            x => out.push(x),
+/// Returns whether `code` is a compiler error code like E0382, as opposed to the name of a lint.
+fn is_error_code(code: &str) -> bool {
+    code.len() == 5 && code.starts_with('E') && code[1..].chars().all(|c| c.is_ascii_digit())
+}
+
+/// Returns outputs for an error explanation written in Markdown. Code blocks in explanations are
+/// rustdoc-style, so they're rewritten as Rust code blocks without rustdoc's hidden lines.
+fn explanation_outputs(markdown: &str) -> EvalOutputs {
+    let mut text = String::new();
+    let mut normalized_markdown = String::new();
+    let mut in_code_block = false;
+    let mut in_rust_code_block = false;
+    for line in markdown.lines() {
+        if let Some(info) = line.trim_start().strip_prefix("```") {
+            if in_code_block {
+                normalized_markdown.push_str("```\n");
+            } else {
+                let info = info.trim();
+                in_rust_code_block = info.is_empty()
+                    || info.split(',').any(|attribute| {
+                        matches!(
+                            attribute.split_whitespace().next(),
+                            Some("rust" | "compile_fail" | "ignore" | "no_run" | "should_panic")
+                        ) || attribute.starts_with("edition")
+                            || is_error_code(attribute)
+                    });
+                normalized_markdown.push_str(if in_rust_code_block {
+                    "```rust\n"
+                } else {
+                    "```text\n"
+                });
+            }
+            in_code_block = !in_code_block;
+            continue;
         }
+        if in_code_block {
+            if in_rust_code_block && (line == "#" || line.starts_with("# ")) {
+                continue;
+            }
+            if !line.is_empty() {
+                text.push_str("    ");
+            }
+        }
+        text.push_str(line);
+        text.push('\n');
+        normalized_markdown.push_str(line);
+        normalized_markdown.push('\n');
     }
+    let mut outputs = EvalOutputs::new();
+    outputs
+        .content_by_mime_type
+        .insert("text/plain".to_owned(), text);
+    outputs
+        .content_by_mime_type
+        .insert("text/markdown".to_owned(), normalized_markdown);
+    outputs
 }
 
 fn text_output<T: Into<String>>(text: T) -> Result<EvalOutputs, Error> {
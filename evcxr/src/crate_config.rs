@@ -51,7 +51,7 @@ fn make_paths_absolute(config: String) -> Result<String, Error> {
 }
 
 /// Escapes a TOML string, see https://toml.io/en/v1.0.0#string
-fn escape_toml_string(string: &str) -> String {
+pub(crate) fn escape_toml_string(string: &str) -> String {
     let mut escaped = String::new();
 
     for char in string.chars() {
@@ -0,0 +1,422 @@
+// Copyright 2023 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Extraction of individual functions from assembly, LLVM IR, MIR and macro-expanded code emitted
+//! by rustc.
+
+use crate::errors::bail;
+use crate::errors::html_escape;
+use crate::errors::Error;
+use once_cell::sync::OnceCell;
+use regex::Captures;
+use regex::Regex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum EmitKind {
+    Asm,
+    LlvmIr,
+}
+
+impl EmitKind {
+    /// The value to pass to rustc's --emit flag.
+    pub(crate) fn rustc_emit(self) -> &'static str {
+        match self {
+            EmitKind::Asm => "asm",
+            EmitKind::LlvmIr => "llvm-ir",
+        }
+    }
+
+    pub(crate) fn file_extension(self) -> &'static str {
+        match self {
+            EmitKind::Asm => "s",
+            EmitKind::LlvmIr => "ll",
+        }
+    }
+
+    /// Returns the mangled name of the function that starts at `line`, if any.
+    fn function_start(self, line: &str) -> Option<&str> {
+        match self {
+            EmitKind::Asm => {
+                let label = line.strip_suffix(':')?;
+                (!label.starts_with('.') && !label.contains(char::is_whitespace)).then_some(label)
+            }
+            EmitKind::LlvmIr => {
+                if !line.starts_with("define ") {
+                    return None;
+                }
+                let name = &line[line.find('@')? + 1..];
+                Some(name.split(['(', ' ']).next()?.trim_matches('"'))
+            }
+        }
+    }
+
+    fn is_function_end(self, line: &str) -> bool {
+        match self {
+            EmitKind::Asm => line.starts_with(".Lfunc_end"),
+            EmitKind::LlvmIr => line == "}",
+        }
+    }
+
+    /// Returns whether `line` should be shown to the user. We filter out assembler directives,
+    /// since they're mostly noise.
+    fn is_interesting(self, line: &str) -> bool {
+        match self {
+            EmitKind::Asm => {
+                let trimmed = line.trim_start();
+                !trimmed.starts_with('.') || trimmed.ends_with(':')
+            }
+            EmitKind::LlvmIr => true,
+        }
+    }
+}
+
+/// Returns the code for the user function `function` (e.g. "foo" or "Foo::bar") from `emitted`,
+/// together with any closures that it contains. Symbols are demangled. `crate_name` is the name
+/// of the crate in which user code was compiled.
+pub(crate) fn extract_function(
+    kind: EmitKind,
+    emitted: &str,
+    crate_name: &str,
+    function: &str,
+) -> Result<String, Error> {
+    let closure_prefix = format!("{function}::{{{{closure}}}}");
+    let mut output = String::new();
+    let mut user_functions = Vec::new();
+    let mut lines = emitted.lines();
+    while let Some(line) = lines.next() {
+        let Some(symbol) = kind.function_start(line) else {
+            continue;
+        };
+        let name = user_function_name(symbol, crate_name);
+        if name != function && !name.starts_with(&closure_prefix) {
+            if !name.is_empty() {
+                user_functions.push(name);
+            }
+            continue;
+        }
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        output.push_str(&demangle_symbols(line));
+        output.push('\n');
+        for line in lines.by_ref() {
+            if kind.is_function_end(line) {
+                if kind == EmitKind::LlvmIr {
+                    output.push_str(line);
+                    output.push('\n');
+                }
+                break;
+            }
+            if kind.is_interesting(line) {
+                output.push_str(&demangle_symbols(line));
+                output.push('\n');
+            }
+        }
+    }
+    if output.is_empty() {
+        user_functions.sort();
+        user_functions.dedup();
+        bail!(
+            "Function `{}` wasn't found. Note, generic functions are only compiled once they're \
+             used with concrete types. Available functions: {}",
+            function,
+            user_functions.join(", ")
+        );
+    }
+    Ok(output)
+}
+
+/// Returns the name of the function with the specified mangled symbol, relative to the crate
+/// `crate_name`, or an empty string if it's not a function defined by the user.
+fn user_function_name(symbol: &str, crate_name: &str) -> String {
+    let Ok(demangled) = rustc_demangle::try_demangle(symbol) else {
+        return String::new();
+    };
+    let demangled = format!("{demangled:#}");
+    let crate_prefix = format!("{crate_name}::");
+    if !demangled.contains(&crate_prefix) {
+        return String::new();
+    }
+    let name = demangled.replace(&crate_prefix, "");
+    if is_generated_function(&name) {
+        return String::new();
+    }
+    name
+}
+
+/// Returns whether `name` is a function that we generate. They all start with evcxr or
+/// run_user_code.
+fn is_generated_function(name: &str) -> bool {
+    name.starts_with("evcxr") || name.starts_with("run_user_code")
+}
+
+/// Returns the MIR for the user function `function` from the output of `-Zunpretty=mir`, together
+/// with any closures that it contains. Methods can be given as either `method` or `Type::method`,
+/// since rustc doesn't name the type of an impl block.
+pub(crate) fn extract_mir_function(mir: &str, function: &str) -> Result<String, Error> {
+    static IMPL_RE: OnceCell<Regex> = OnceCell::new();
+    let impl_re = IMPL_RE.get_or_init(|| Regex::new("<impl at [^>]*>").unwrap());
+    let closure_prefix = format!("{function}::{{closure");
+    let method = format!(
+        "<impl>::{}",
+        function.rsplit("::").next().unwrap_or_default()
+    );
+    let mut output = String::new();
+    let mut user_functions = Vec::new();
+    let mut lines = mir.lines();
+    while let Some(line) = lines.next() {
+        let Some(name) = line
+            .strip_prefix("fn ")
+            .and_then(|rest| rest.split('(').next())
+        else {
+            continue;
+        };
+        let name = impl_re.replace_all(name, "<impl>");
+        if name != function && name != method && !name.starts_with(&closure_prefix) {
+            if !is_generated_function(&name) && !name.contains("{closure") {
+                user_functions.push(name.into_owned());
+            }
+            continue;
+        }
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        output.push_str(line);
+        output.push('\n');
+        for line in lines.by_ref() {
+            output.push_str(line);
+            output.push('\n');
+            if line == "}" {
+                break;
+            }
+        }
+    }
+    if output.is_empty() {
+        user_functions.sort();
+        user_functions.dedup();
+        bail!(
+            "Function `{}` wasn't found. Available functions: {}",
+            function,
+            user_functions.join(", ")
+        );
+    }
+    Ok(output)
+}
+
+/// Returns the body of the function `function`, which takes no arguments, from the output of
+/// `-Zunpretty=expanded`.
+pub(crate) fn extract_function_body(expanded: &str, function: &str) -> Result<String, Error> {
+    let header = format!("fn {function}() {{");
+    let mut lines = expanded.lines();
+    let Some(first_line) = lines.find(|line| line.starts_with(&header)) else {
+        bail!("Couldn't find `{}` in the expanded code", function);
+    };
+    // Short functions are printed on a single line.
+    let rest = &first_line[header.len()..];
+    if let Some(body) = rest.strip_suffix('}') {
+        return Ok(format!("{}\n", body.trim()));
+    }
+    let mut body = String::new();
+    for line in lines.take_while(|line| *line != "}") {
+        body.push_str(line.strip_prefix("    ").unwrap_or(line));
+        body.push('\n');
+    }
+    Ok(body)
+}
+
+/// Replaces mangled symbols within `line` with their demangled form.
+fn demangle_symbols(line: &str) -> String {
+    static SYMBOL_RE: OnceCell<Regex> = OnceCell::new();
+    let symbol_re =
+        SYMBOL_RE.get_or_init(|| Regex::new(r"_(ZN[0-9A-Za-z_$.]+E|R[0-9A-Za-z_]+)").unwrap());
+    symbol_re
+        .replace_all(line, |captures: &Captures| {
+            let symbol = &captures[0];
+            match rustc_demangle::try_demangle(symbol) {
+                Ok(demangled) => format!("{demangled:#}"),
+                Err(_) => symbol.to_owned(),
+            }
+        })
+        .into_owned()
+}
+
+/// Returns `code`, which was returned by `extract_function`, as syntax-highlighted HTML.
+pub(crate) fn to_html(kind: EmitKind, code: &str) -> String {
+    const LABEL_COLOR: &str = "#6f42c1";
+    const INSTRUCTION_COLOR: &str = "#005cc5";
+    const COMMENT_COLOR: &str = "#6a737d";
+    let comment_marker = match kind {
+        EmitKind::Asm => '#',
+        EmitKind::LlvmIr => ';',
+    };
+    let mut html = String::from("<pre>");
+    for line in code.lines() {
+        let (code, comment) = match line.find(comment_marker) {
+            Some(index) => line.split_at(index),
+            None => (line, ""),
+        };
+        let trimmed = code.trim_start();
+        let indent = &code[..code.len() - trimmed.len()];
+        html.push_str(indent);
+        if trimmed.ends_with(':') || kind.function_start(code).is_some() {
+            html.push_str(&format!("<span style=\"color: {LABEL_COLOR};\">"));
+            html_escape(trimmed, &mut html);
+            html.push_str("</span>");
+        } else if !trimmed.is_empty() {
+            // Highlight the instruction, which for assembly is the first word, and for LLVM IR is
+            // the first word after any assignment.
+            let instruction_start = match kind {
+                EmitKind::LlvmIr => trimmed.find(" = ").map_or(0, |index| index + 3),
+                EmitKind::Asm => 0,
+            };
+            let instruction_end = trimmed[instruction_start..]
+                .find(char::is_whitespace)
+                .map_or(trimmed.len(), |index| instruction_start + index);
+            html_escape(&trimmed[..instruction_start], &mut html);
+            html.push_str(&format!(
+                "<span style=\"color: {INSTRUCTION_COLOR}; font-weight: bold;\">"
+            ));
+            html_escape(&trimmed[instruction_start..instruction_end], &mut html);
+            html.push_str("</span>");
+            html_escape(&trimmed[instruction_end..], &mut html);
+        }
+        if !comment.is_empty() {
+            html.push_str(&format!("<span style=\"color: {COMMENT_COLOR};\">"));
+            html_escape(comment, &mut html);
+            html.push_str("</span>");
+        }
+        html.push('\n');
+    }
+    html.push_str("</pre>");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ASM: &str = "\
+\t.section\t.text._ZN3ctx7add_one17h0123456789abcdefE,\"ax\",@progbits
+\t.p2align\t4, 0x90
+\t.type\t_ZN3ctx7add_one17h0123456789abcdefE,@function
+_ZN3ctx7add_one17h0123456789abcdefE:
+\t.cfi_startproc
+\tleaq\t(%rdi,%rdi,2), %rax
+\tincq\t%rax
+\tretq
+.Lfunc_end0:
+\t.size\t_ZN3ctx7add_one17h0123456789abcdefE, .Lfunc_end0-_ZN3ctx7add_one17h0123456789abcdefE
+_ZN3ctx15run_user_code_117h0123456789abcdefE:
+\tcallq\t_ZN3ctx7add_one17h0123456789abcdefE
+\tretq
+.Lfunc_end1:
+";
+
+    #[test]
+    fn extract_asm() {
+        assert_eq!(
+            extract_function(EmitKind::Asm, ASM, "ctx", "add_one").unwrap(),
+            "ctx::add_one:\n\tleaq\t(%rdi,%rdi,2), %rax\n\tincq\t%rax\n\tretq\n"
+        );
+        let error = extract_function(EmitKind::Asm, ASM, "ctx", "foo")
+            .unwrap_err()
+            .to_string();
+        assert!(error.ends_with("Available functions: add_one"), "{error}");
+    }
+
+    #[test]
+    fn extract_llvm_ir() {
+        let ir = "\
+; ctx::add_one
+; Function Attrs: nounwind
+define internal i64 @_ZN3ctx7add_one17h0123456789abcdefE(i64 %x) unnamed_addr #0 {
+start:
+  %0 = mul i64 %x, 3
+  ret i64 %0
+}
+
+define void @run_user_code_1() {
+  ret void
+}
+";
+        assert_eq!(
+            extract_function(EmitKind::LlvmIr, ir, "ctx", "add_one").unwrap(),
+            "define internal i64 @ctx::add_one(i64 %x) unnamed_addr #0 {\nstart:\n  \
+             %0 = mul i64 %x, 3\n  ret i64 %0\n}\n"
+        );
+    }
+
+    #[test]
+    fn extract_mir() {
+        let mir = "\
+// WARNING: This output format is intended for human consumers only
+fn add_one(_1: u64) -> u64 {
+    bb0: {
+        _0 = Add(copy _1, const 1_u64);
+        return;
+    }
+}
+
+fn add_one::{closure#0}(_1: &{closure@src/lib.rs:1:37: 1:45}) -> u64 {
+    bb0: {
+        return;
+    }
+}
+
+alloc1 (size: 4, align: 1) {
+    c0 01 0a 00                                     │ ....
+}
+
+fn <impl at src/lib.rs:3:1: 3:9>::bar(_1: &Foo) -> i32 {
+    bb0: {
+        _0 = const 1_i32;
+        return;
+    }
+}
+
+fn run_user_code_1(_1: *mut c_void) -> *mut c_void {
+}
+";
+        let add_one = extract_mir_function(mir, "add_one").unwrap();
+        assert!(add_one.starts_with("fn add_one(_1: u64) -> u64 {\n"));
+        assert!(add_one.contains("\n}\n\nfn add_one::{closure#0}("));
+        assert!(!add_one.contains("alloc1"));
+        let bar = extract_mir_function(mir, "Foo::bar").unwrap();
+        assert!(bar.starts_with("fn <impl at src/lib.rs:3:1: 3:9>::bar("));
+        let error = extract_mir_function(mir, "foo").unwrap_err().to_string();
+        assert!(
+            error.ends_with("Available functions: <impl>::bar, add_one"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn extract_body() {
+        let expanded = "\
+fn foo() { 1 }
+fn evcxr_expanded_user_code() {
+    let v = ::alloc::vec::from_elem(0, 2);
+    struct A;
+}
+";
+        assert_eq!(
+            extract_function_body(expanded, "evcxr_expanded_user_code").unwrap(),
+            "let v = ::alloc::vec::from_elem(0, 2);\nstruct A;\n"
+        );
+        assert_eq!(
+            extract_function_body("fn f() { let x = 1; }\n", "f").unwrap(),
+            "let x = 1;\n"
+        );
+    }
+
+    #[test]
+    fn html() {
+        let html = to_html(EmitKind::Asm, "ctx::add_one:\n\tretq # <done>\n");
+        assert!(html.contains("font-weight: bold;\">retq</span>"), "{html}");
+        assert!(html.contains("# &lt;done&gt;"), "{html}");
+    }
+}
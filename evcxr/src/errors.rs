@@ -31,6 +31,15 @@ pub struct CompilationError {
     spanned_messages: Vec<SpannedMessage>,
     spanned_helps: Vec<SpannedMessage>,
     level: String,
+    /// Machine-applicable suggestions from the compiler that apply to the user's code.
+    suggestions: Vec<Suggestion>,
+}
+
+/// A replacement, suggested by the compiler, for part of the user's code.
+#[derive(Debug, Clone)]
+pub(crate) struct Suggestion {
+    pub(crate) span: Span,
+    pub(crate) replacement: String,
 }
 
 pub enum Theme {
@@ -64,8 +73,12 @@ impl CompilationError {
         if !source.is_ascii() {
             return None;
         }
-        let mut builder =
-            Report::build(ReportKind::Error, file_name.clone(), 0).with_message(error.message());
+        let kind = if error.level() == "warning" {
+            ReportKind::Warning
+        } else {
+            ReportKind::Error
+        };
+        let mut builder = Report::build(kind, file_name.clone(), 0).with_message(error.message());
         let mut next_color = {
             let mut colors = ColorGenerator::new();
             move || {
@@ -109,6 +122,200 @@ impl CompilationError {
         }
         Some(builder.finish())
     }
+
+    /// Returns an HTML rendering of this error, similar to the report produced by `build_report`.
+    /// `source` should be the code that the user supplied. Styles are inline, so that the result
+    /// renders the same in notebooks and when exported.
+    pub fn build_html_report(&self, source: &str) -> String {
+        let mut out = String::new();
+        out.push_str("<div style=\"font-family: monospace;\">");
+        write!(
+            out,
+            "<div><span style=\"color: {}; font-weight: bold;\">{}",
+            if self.level() == "warning" {
+                WARNING_COLOR
+            } else {
+                ERROR_COLOR
+            },
+            self.level()
+        )
+        .unwrap();
+        if let Some(code) = self.code() {
+            out.push('[');
+            out.push_str(&error_code_link(code));
+            out.push(']');
+        }
+        out.push_str(":</span> <b>");
+        html_escape(&self.message(), &mut out);
+        out.push_str("</b></div>");
+
+        let labelled: Vec<&SpannedMessage> = self
+            .spanned_messages()
+            .iter()
+            .chain(self.help_spanned().iter())
+            .filter(|message| message.span.is_some() && !message.label.is_empty())
+            .collect();
+        let source_lines: Vec<&str> = source.lines().collect();
+        if let (Some(first_line), Some(last_line)) = (
+            labelled
+                .iter()
+                .filter_map(|m| m.span)
+                .map(|s| s.start_line)
+                .min(),
+            labelled
+                .iter()
+                .filter_map(|m| m.span)
+                .map(|s| s.end_line)
+                .max(),
+        ) {
+            let last_line = last_line.min(source_lines.len());
+            let gutter_width = last_line.to_string().len();
+            out.push_str("<pre style=\"margin: 0.5em 0;\">");
+            for line_number in first_line..=last_line {
+                let line = source_lines[line_number - 1];
+                write!(
+                    out,
+                    "<span style=\"color: {GUTTER_COLOR};\">{line_number:>gutter_width$} | </span>"
+                )
+                .unwrap();
+                // Highlight characters that are covered by a span, preferring primary spans. Runs
+                // of characters with the same color are grouped.
+                let mut runs: Vec<(Option<&'static str>, String)> = Vec::new();
+                for (column, ch) in (1..).zip(line.chars()) {
+                    let color = labelled
+                        .iter()
+                        .filter(|m| m.span.map_or(false, |s| s.contains(line_number, column)))
+                        .max_by_key(|m| m.is_primary)
+                        .map(|m| label_color(m));
+                    match runs.last_mut() {
+                        Some((run_color, text)) if *run_color == color => text.push(ch),
+                        _ => runs.push((color, ch.to_string())),
+                    }
+                }
+                for (color, text) in runs {
+                    if let Some(color) = color {
+                        write!(
+                            out,
+                            "<span style=\"color: {color}; font-weight: bold; text-decoration: \
+                             underline;\">"
+                        )
+                        .unwrap();
+                        html_escape(&text, &mut out);
+                        out.push_str("</span>");
+                    } else {
+                        html_escape(&text, &mut out);
+                    }
+                }
+                out.push('\n');
+                // Labels go under the last line of their span.
+                for message in &labelled {
+                    let Some(span) = message.span else {
+                        continue;
+                    };
+                    if span.end_line.min(last_line) != line_number {
+                        continue;
+                    }
+                    let (start_column, end_column) = if span.start_line == span.end_line {
+                        (span.start_column, span.end_column)
+                    } else {
+                        (1, span.end_column)
+                    };
+                    write!(
+                        out,
+                        "{:gutter_width$} | {}<span style=\"color: {};\">{} ",
+                        "",
+                        " ".repeat(start_column.saturating_sub(1)),
+                        label_color(message),
+                        "^".repeat(end_column.saturating_sub(start_column).max(1))
+                    )
+                    .unwrap();
+                    html_escape(&message.label, &mut out);
+                    out.push_str("</span>\n");
+                }
+            }
+            out.push_str("</pre>");
+        }
+
+        let mut notes: Vec<String> = self
+            .spanned_messages()
+            .iter()
+            .filter(|message| message.span.is_none() && !message.label.is_empty())
+            .map(|message| message.label.clone())
+            .collect();
+        notes.extend(evcxr_specific_notes(self).map(str::to_owned));
+        notes.extend(self.evcxr_extra_hint().map(str::to_owned));
+        for (kind, text) in notes
+            .iter()
+            .map(|note| ("note", note))
+            .chain(self.help().iter().map(|help| ("help", help)))
+        {
+            write!(
+                out,
+                "<div><span style=\"color: {HELP_COLOR}; font-weight: bold;\">{kind}:</span> "
+            )
+            .unwrap();
+            if text.contains('\n') {
+                out.push_str("<pre style=\"margin: 0;\">");
+                html_escape(text, &mut out);
+                out.push_str("</pre>");
+            } else {
+                html_escape(text, &mut out);
+            }
+            out.push_str("</div>");
+        }
+        if let (Some(code), Some(_)) = (self.code(), self.explanation()) {
+            write!(
+                out,
+                "<div>For more information about this error, run <code>:explain</code> or see {}.\
+                 </div>",
+                error_code_link(code)
+            )
+            .unwrap();
+        }
+        out.push_str("</div>");
+        out
+    }
+}
+
+const ERROR_COLOR: &str = "#d73a49";
+const WARNING_COLOR: &str = "#b08800";
+const SECONDARY_COLOR: &str = "#0366d6";
+const HELP_COLOR: &str = "#22863a";
+const GUTTER_COLOR: &str = "#6a737d";
+
+fn label_color(message: &SpannedMessage) -> &'static str {
+    if message.is_primary {
+        ERROR_COLOR
+    } else {
+        SECONDARY_COLOR
+    }
+}
+
+/// Returns a link to the documentation for the error code `code`, e.g. "E0382".
+fn error_code_link(code: &str) -> String {
+    let mut link = String::new();
+    if code.chars().all(|c| c.is_ascii_alphanumeric()) {
+        write!(
+            link,
+            "<a href=\"https://doc.rust-lang.org/error_codes/{code}.html\" target=\"_blank\">\
+             {code}</a>"
+        )
+        .unwrap();
+    } else {
+        html_escape(code, &mut link);
+    }
+    link
+}
+
+pub(crate) fn html_escape(input: &str, out: &mut String) {
+    for ch in input.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            x => out.push(x),
+        }
+    }
 }
 
 fn evcxr_specific_notes(error: &CompilationError) -> Option<&'static str> {
@@ -171,6 +378,7 @@ impl CompilationError {
         if json["message"].is_object() {
             json = json["message"].clone();
         }
+        let suggestions = machine_applicable_suggestions(&json, code_block);
         let mut code_origins = get_code_origins(&json, code_block);
         let mut user_error_json = None;
         if let JsonValue::Array(children) = &json["children"] {
@@ -217,6 +425,7 @@ impl CompilationError {
             level: json["level"].as_str().unwrap_or("").to_owned(),
             json,
             code_origins: code_origins.into_iter().cloned().collect(),
+            suggestions,
         })
     }
 
@@ -245,6 +454,7 @@ impl CompilationError {
             json: JsonValue::Null,
             code_origins: vec![segment.kind.clone()],
             level: "error".to_owned(),
+            suggestions: Vec::new(),
         }
     }
 
@@ -253,11 +463,23 @@ impl CompilationError {
         self.code_origins.iter().any(CodeKind::is_user_supplied)
     }
 
+    /// Returns whether this error originated in the code currently being evaluated, as opposed to
+    /// code from an earlier evaluation.
+    pub(crate) fn is_from_original_user_code(&self) -> bool {
+        self.code_origins
+            .iter()
+            .any(|origin| matches!(origin, CodeKind::OriginalUserCode(_)))
+    }
+
     /// Returns whether this error originated in code that we generated.
     pub fn is_from_generated_code(&self) -> bool {
         self.code_origins.contains(&CodeKind::OtherGeneratedCode)
     }
 
+    pub(crate) fn suggestions(&self) -> &[Suggestion] {
+        &self.suggestions
+    }
+
     pub fn message(&self) -> String {
         self.message.clone()
     }
@@ -382,6 +604,124 @@ fn build_spanned_messages(json: &JsonValue, code_block: &CodeBlock) -> Vec<Spann
     output_spans
 }
 
+/// Returns the location in the user's code of the span `span_json` from a compiler diagnostic, if
+/// it's within code that the user supplied.
+fn user_span_from_json(span_json: &JsonValue, code_block: &CodeBlock) -> Option<Span> {
+    if let (Some(file_name), Some(start_column), Some(end_column)) = (
+        span_json["file_name"].as_str(),
+        span_json["column_start"].as_usize(),
+        span_json["column_end"].as_usize(),
+    ) {
+        if file_name.ends_with("lib.rs") {
+            let origins = get_code_origins_for_span(span_json, code_block);
+            if let (
+                Some((CodeKind::OriginalUserCode(start), start_line_offset)),
+                Some((CodeKind::OriginalUserCode(end), end_line_offset)),
+            ) = (origins.first(), origins.last())
+            {
+                Some(Span {
+                    start_line: start.start_line + start_line_offset,
+                    start_column: start_column
+                        + (if *start_line_offset == 0 {
+                            start.column_offset
+                        } else {
+                            0
+                        }),
+                    end_line: end.start_line + end_line_offset,
+                    end_column: end_column
+                        + (if *end_line_offset == 0 {
+                            end.column_offset
+                        } else {
+                            0
+                        }),
+                })
+            } else {
+                // Spans within generated code won't mean anything to the user, suppress
+                // them.
+                None
+            }
+        } else {
+            None
+        }
+    } else {
+        None
+    }
+}
+
+/// Returns the machine-applicable suggestions in `json`, a compiler diagnostic, that apply to code
+/// supplied by the user.
+fn machine_applicable_suggestions(json: &JsonValue, code_block: &CodeBlock) -> Vec<Suggestion> {
+    let mut suggestions = Vec::new();
+    for span_json in json["spans"].members() {
+        if span_json["suggestion_applicability"] != "MachineApplicable" {
+            continue;
+        }
+        if let (Some(replacement), Some(span)) = (
+            span_json["suggested_replacement"].as_str(),
+            user_span_from_json(span_json, code_block),
+        ) {
+            suggestions.push(Suggestion {
+                span,
+                replacement: replacement.to_owned(),
+            });
+        }
+    }
+    for child in json["children"].members() {
+        suggestions.extend(machine_applicable_suggestions(child, code_block));
+    }
+    suggestions
+}
+
+/// Returns `source` with `suggestions` applied. Suggestions that overlap an earlier suggestion are
+/// skipped, as are any that don't fit within `source`.
+pub(crate) fn apply_suggestions(source: &str, suggestions: &[&Suggestion]) -> String {
+    let mut ranges: Vec<(Range<usize>, &str)> = suggestions
+        .iter()
+        .filter_map(|suggestion| {
+            let start = char_position_to_byte_offset(
+                source,
+                suggestion.span.start_line,
+                suggestion.span.start_column,
+            )?;
+            let end = char_position_to_byte_offset(
+                source,
+                suggestion.span.end_line,
+                suggestion.span.end_column,
+            )?;
+            Some((start..end, suggestion.replacement.as_str()))
+        })
+        .collect();
+    ranges.sort_by_key(|(range, _)| (range.start, range.end));
+    let mut output = String::new();
+    let mut position = 0;
+    for (range, replacement) in ranges {
+        if range.start < position || range.end < range.start {
+            continue;
+        }
+        output.push_str(&source[position..range.start]);
+        output.push_str(replacement);
+        position = range.end;
+    }
+    output.push_str(&source[position..]);
+    output
+}
+
+/// Returns the byte offset in `source` of the 1-based line and character column.
+fn char_position_to_byte_offset(source: &str, line: usize, column: usize) -> Option<usize> {
+    let mut line_start = 0;
+    for _ in 1..line {
+        line_start += source[line_start..].find('\n')? + 1;
+    }
+    let line_text = source[line_start..].split('\n').next()?;
+    if column - 1 == line_text.chars().count() {
+        return Some(line_start + line_text.len());
+    }
+    line_text
+        .char_indices()
+        .nth(column - 1)
+        .map(|(offset, _)| line_start + offset)
+}
+
 #[non_exhaustive]
 #[derive(Debug, Clone, Copy)]
 pub struct Span {
@@ -398,6 +738,12 @@ pub struct Span {
 }
 
 impl Span {
+    /// Returns whether the character at the specified 1-based line and column is within this span.
+    fn contains(&self, line: usize, column: usize) -> bool {
+        (line, column) >= (self.start_line, self.start_column)
+            && (line, column) < (self.end_line, self.end_column)
+    }
+
     pub(crate) fn from_command(
         command: &CommandCall,
         start_column: usize,
@@ -469,45 +815,7 @@ impl SpannedMessage {
         code_block: &CodeBlock,
         fallback_label: Option<String>,
     ) -> SpannedMessage {
-        let span = if let (Some(file_name), Some(start_column), Some(end_column)) = (
-            span_json["file_name"].as_str(),
-            span_json["column_start"].as_usize(),
-            span_json["column_end"].as_usize(),
-        ) {
-            if file_name.ends_with("lib.rs") {
-                let origins = get_code_origins_for_span(span_json, code_block);
-                if let (
-                    Some((CodeKind::OriginalUserCode(start), start_line_offset)),
-                    Some((CodeKind::OriginalUserCode(end), end_line_offset)),
-                ) = (origins.first(), origins.last())
-                {
-                    Some(Span {
-                        start_line: start.start_line + start_line_offset,
-                        start_column: start_column
-                            + (if *start_line_offset == 0 {
-                                start.column_offset
-                            } else {
-                                0
-                            }),
-                        end_line: end.start_line + end_line_offset,
-                        end_column: end_column
-                            + (if *end_line_offset == 0 {
-                                end.column_offset
-                            } else {
-                                0
-                            }),
-                    })
-                } else {
-                    // Spans within generated code won't mean anything to the user, suppress
-                    // them.
-                    None
-                }
-            } else {
-                None
-            }
-        } else {
-            None
-        };
+        let span = user_span_from_json(span_json, code_block);
         if span.is_none() {
             let expansion_span_json = &span_json["expansion"]["span"];
             if !expansion_span_json.is_empty() {
@@ -553,6 +861,8 @@ pub enum Error {
     TypeRedefinedVariablesLost(Vec<String>),
     Message(String),
     SubprocessTerminated(String),
+    /// The evaluation was interrupted before it completed.
+    Cancelled,
 }
 
 impl std::error::Error for Error {}
@@ -575,6 +885,7 @@ impl fmt::Display for Error {
             Error::Message(message) | Error::SubprocessTerminated(message) => {
                 write!(f, "{message}")?
             }
+            Error::Cancelled => write!(f, "Evaluation cancelled")?,
         }
         Ok(())
     }
@@ -12,13 +12,18 @@ use crate::code_block::CodeKind;
 use crate::code_block::Segment;
 use crate::code_block::UserCodeInfo;
 use crate::crate_config::ExternalCrate;
+use crate::emit;
+use crate::emit::EmitKind;
 use crate::errors::bail;
 use crate::errors::CompilationError;
 use crate::errors::Error;
 use crate::errors::Span;
 use crate::errors::SpannedMessage;
 use crate::evcxr_internal_runtime;
+use crate::interrupt::InterruptHandle;
 use crate::item;
+use crate::module;
+use crate::module::CheckCommand;
 use crate::module::Module;
 use crate::module::SoFile;
 use crate::runtime;
@@ -26,6 +31,8 @@ use crate::rust_analyzer::Completions;
 use crate::rust_analyzer::RustAnalyzer;
 use crate::rust_analyzer::TypeName;
 use crate::rust_analyzer::VariableInfo;
+use crate::test_runner;
+use crate::test_runner::TestResult;
 use crate::use_trees::Import;
 use anyhow::Result;
 use once_cell::sync::OnceCell;
@@ -35,6 +42,7 @@ use ra_ap_syntax::AstNode;
 use ra_ap_syntax::SyntaxKind;
 use ra_ap_syntax::SyntaxNode;
 use regex::Regex;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::path::Path;
@@ -56,8 +64,10 @@ pub struct EvalContext {
     module: Module,
     committed_state: ContextState,
     stdout_sender: crossbeam_channel::Sender<String>,
+    comm_sender: crossbeam_channel::Sender<String>,
     analyzer: RustAnalyzer,
     initial_config: Config,
+    interrupt_handle: InterruptHandle,
 }
 
 #[derive(Clone, Debug)]
@@ -90,6 +100,27 @@ pub(crate) struct Config {
     pub(crate) toolchain: String,
     cargo_path: String,
     pub(crate) rustc_path: String,
+    pub(crate) warnings: Warnings,
+}
+
+/// What we do with compiler warnings in user code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Warnings {
+    Off,
+    /// Warnings are reported in `EvalOutputs::warnings`.
+    On,
+    /// Warnings are reported as errors and prevent the code from running.
+    Deny,
+}
+
+impl Warnings {
+    fn name(self) -> &'static str {
+        match self {
+            Warnings::Off => "off",
+            Warnings::On => "on",
+            Warnings::Deny => "deny",
+        }
+    }
 }
 
 fn create_initial_config(crate_dir: PathBuf) -> Config {
@@ -124,6 +155,7 @@ impl Config {
             toolchain: String::new(),
             cargo_path: default_cargo_path(),
             rustc_path: default_rustc_path(),
+            warnings: Warnings::On,
         }
     }
 
@@ -153,9 +185,6 @@ impl Config {
         if self.linker == "mold" {
             command.arg("-run").arg(&self.cargo_path);
         }
-        if !self.toolchain.is_empty() {
-            command.arg(format!("+{}", self.toolchain));
-        }
         if self.offline_mode {
             command.arg("--offline");
         }
@@ -235,12 +264,21 @@ const GET_TYPE_NAME_DEF: &str = stringify!(
 );
 
 const PANIC_NOTIFICATION: &str = "EVCXR_PANIC_NOTIFICATION";
+/// Prefix of the pseudo mime types with which variable previews are sent by the subprocess.
+const PREVIEW_MIME_PREFIX: &str = "evcxr-variable-preview/";
+
+/// The function in which user code is placed by `EvalContext::expanded_code`.
+const EXPANDED_CODE_FN_NAME: &str = "evcxr_expanded_user_code";
 
 // Outputs from an EvalContext. This is a separate struct since users may want
 // destructure this and pass its components to separate threads.
 pub struct EvalContextOutputs {
     pub stdout: crossbeam_channel::Receiver<String>,
     pub stderr: crossbeam_channel::Receiver<String>,
+    /// Comm messages sent by user code via evcxr_runtime. Each is a JSON object containing the
+    /// fields of a comm_open, comm_msg or comm_close message plus "type", which is one of "open",
+    /// "msg" or "close".
+    pub comm: crossbeam_channel::Receiver<String>,
 }
 
 #[non_exhaustive]
@@ -335,7 +373,9 @@ impl EvalContext {
 
         let (stdout_sender, stdout_receiver) = crossbeam_channel::unbounded();
         let (stderr_sender, stderr_receiver) = crossbeam_channel::unbounded();
+        let (comm_sender, comm_receiver) = crossbeam_channel::unbounded();
         let child_process = ChildProcess::new(subprocess_command, stderr_sender)?;
+        let interrupt_handle = InterruptHandle::new(child_process.process_handle());
         let initial_config = create_initial_config(module.crate_dir().to_owned());
         let initial_state = ContextState::new(initial_config.clone());
         let mut context = EvalContext {
@@ -344,12 +384,15 @@ impl EvalContext {
             module,
             child_process,
             stdout_sender,
+            comm_sender,
             analyzer,
             initial_config,
+            interrupt_handle,
         };
         let outputs = EvalContextOutputs {
             stdout: stdout_receiver,
             stderr: stderr_receiver,
+            comm: comm_receiver,
         };
         if context.committed_state.linker() == "lld" && context.eval("42").is_err() {
             context.committed_state.set_linker("system".to_owned());
@@ -395,17 +438,38 @@ impl EvalContext {
         user_code: CodeBlock,
         mut state: ContextState,
         code_info: &UserCodeInfo,
+        command: CheckCommand,
     ) -> Result<Vec<CompilationError>, Error> {
         state.config.display_final_expression = false;
         state.config.expand_use_statements = false;
         let user_code = state.apply(user_code, &code_info.nodes)?;
         let code = state.analysis_code(user_code.clone());
-        let errors = self.module.check(&code, &state.config)?;
+        let mut errors = self.module.check(&code, &state.config, command)?;
+        if command == CheckCommand::Clippy {
+            // Lints in code that we generated aren't something that the user can do anything about.
+            errors.retain(|error| error.is_from_user_code() && !error.is_from_generated_code());
+        }
         Ok(state.apply_custom_errors(errors, &user_code, code_info))
     }
 
     /// Evaluates the supplied Rust code.
     pub(crate) fn eval_with_callbacks(
+        &mut self,
+        user_code: CodeBlock,
+        state: ContextState,
+        code_info: &UserCodeInfo,
+        callbacks: &mut EvalCallbacks,
+    ) -> Result<EvalOutputs, Error> {
+        let _guard = self.interrupt_handle.begin_evaluation();
+        let result = self.eval_with_callbacks_internal(user_code, state, code_info, callbacks);
+        if result.is_err() && self.interrupt_handle.is_interrupted() {
+            // Whatever error we got, it was most likely caused by the interruption.
+            return Err(Error::Cancelled);
+        }
+        result
+    }
+
+    fn eval_with_callbacks_internal(
         &mut self,
         user_code: CodeBlock,
         mut state: ContextState,
@@ -494,6 +558,94 @@ impl EvalContext {
         self.module.last_source()
     }
 
+    /// Compiles the items defined so far with the standard test harness, then runs the
+    /// `#[test]` functions whose names contain `filter`.
+    pub(crate) fn run_tests(
+        &mut self,
+        state: &ContextState,
+        filter: Option<&str>,
+    ) -> Result<Vec<TestResult>, Error> {
+        let _guard = self.interrupt_handle.begin_evaluation();
+        self.write_cargo_toml(state)?;
+        let code = CodeBlock::new()
+            .generated("#![allow(unused_imports, unused_mut, dead_code)]")
+            .add_all(state.attributes_code())
+            .add_all(state.items_code());
+        let output = self
+            .module
+            .test(&code, state, filter, &self.interrupt_handle)?;
+        Ok(test_runner::parse_test_output(&output))
+    }
+
+    /// Returns the current toolchain's explanation of the error with the specified code (e.g.
+    /// E0382), as Markdown.
+    pub(crate) fn explain_error_code(&self, code: &str) -> Result<String, Error> {
+        let output = Command::new(&self.committed_state.config.rustc_path)
+            .arg("--explain")
+            .arg(code)
+            .output()?;
+        if !output.status.success() {
+            bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    /// Returns the path to rustfmt for the current toolchain.
+    pub(crate) fn rustfmt_path(&self) -> String {
+        rustup_which(&self.committed_state.config.toolchain, "rustfmt")
+            .unwrap_or_else(|| "rustfmt".to_owned())
+    }
+
+    /// Returns the assembly or LLVM IR for the user-defined function `function`, compiled with
+    /// the items and settings in `state`.
+    pub(crate) fn emitted_code(
+        &mut self,
+        state: &ContextState,
+        kind: EmitKind,
+        function: &str,
+    ) -> Result<String, Error> {
+        let _guard = self.interrupt_handle.begin_evaluation();
+        self.write_cargo_toml(state)?;
+        let code = state.code_to_compile(CodeBlock::new(), CompilationMode::NoCatch);
+        let emitted = self
+            .module
+            .emit(&code, state, kind, &self.interrupt_handle)?;
+        emit::extract_function(kind, &emitted, module::CRATE_NAME, function)
+    }
+
+    /// Returns `user_code` after macro expansion. Items from `state` are available to the code.
+    pub(crate) fn expanded_code(
+        &mut self,
+        state: &ContextState,
+        user_code: CodeBlock,
+    ) -> Result<String, Error> {
+        let _guard = self.interrupt_handle.begin_evaluation();
+        self.write_cargo_toml(state)?;
+        // The user code goes in a function of its own so that we can find it in the output.
+        let code = CodeBlock::new()
+            .generated("#![allow(unused_imports, unused_mut, dead_code)]")
+            .add_all(state.attributes_code())
+            .add_all(state.items_code())
+            .generated(format!("fn {EXPANDED_CODE_FN_NAME}() {{"))
+            .add_all(user_code)
+            .generated("}");
+        let expanded = self
+            .module
+            .unpretty(&code, state, "expanded", &self.interrupt_handle)?;
+        emit::extract_function_body(&expanded, EXPANDED_CODE_FN_NAME)
+    }
+
+    /// Returns the MIR for the user-defined function `function`.
+    pub(crate) fn mir(&mut self, state: &ContextState, function: &str) -> Result<String, Error> {
+        let _guard = self.interrupt_handle.begin_evaluation();
+        self.write_cargo_toml(state)?;
+        let code = state.code_to_compile(CodeBlock::new(), CompilationMode::NoCatch);
+        let mir = self
+            .module
+            .unpretty(&code, state, "mir", &self.interrupt_handle)?;
+        emit::extract_mir_function(&mir, function)
+    }
+
     pub fn set_opt_level(&mut self, level: &str) -> Result<(), Error> {
         self.committed_state.set_opt_level(level)
     }
@@ -517,6 +669,113 @@ impl EvalContext {
             .map(|(v, t)| (v.as_str(), t.type_name.as_str()))
     }
 
+    /// Returns the name, type and mutability of each variable, sorted by name.
+    pub fn variables(&self) -> Vec<Variable> {
+        let mut variables: Vec<Variable> = self
+            .committed_state
+            .variable_states
+            .iter()
+            .map(|(name, state)| Variable {
+                name: name.clone(),
+                type_name: state.type_name.clone(),
+                is_mut: state.is_mut,
+            })
+            .collect();
+        variables.sort_by(|a, b| a.name.cmp(&b.name));
+        variables
+    }
+
+    /// Returns previews of the values of variables, formatted with Debug and truncated to
+    /// `max_preview_chars`, keyed by variable name. Variables whose types don't implement Debug,
+    /// or whose Debug implementations panic, have no preview. All the previews are obtained by
+    /// compiling and running a single piece of code, which only accesses variables by shared
+    /// reference, so variables can't be lost.
+    pub fn variable_previews(
+        &mut self,
+        max_preview_chars: usize,
+    ) -> Result<HashMap<String, String>, Error> {
+        let variables: Vec<String> = self
+            .committed_state
+            .stored_variable_states
+            .keys()
+            .cloned()
+            .collect();
+        if variables.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let previews: Vec<(String, String)> = variables
+            .iter()
+            .map(|name| (format!("{PREVIEW_MIME_PREFIX}{name}"), name.clone()))
+            .collect();
+        // We format one more character than we'll keep so that we know when to add an ellipsis.
+        let outputs = self.run_readonly(
+            CodeBlock::new(),
+            &variables,
+            &previews,
+            Some(max_preview_chars + 1),
+        )?;
+        Ok(outputs
+            .content_by_mime_type
+            .into_iter()
+            .filter_map(|(mime_type, preview)| {
+                let name = mime_type.strip_prefix(PREVIEW_MIME_PREFIX)?.to_owned();
+                let mut truncated: String = preview.chars().take(max_preview_chars).collect();
+                if truncated.len() < preview.len() {
+                    truncated.push('…');
+                }
+                Some((name, truncated))
+            })
+            .collect())
+    }
+
+    /// Evaluates `expression` without changing the state of the session. Variables are only
+    /// available to the expression by shared reference, so it can't move or modify them, even if
+    /// it panics. The value of the expression is returned as text/plain, formatted with Debug, if
+    /// its type implements Debug and formatting it doesn't panic.
+    pub fn eval_readonly(&mut self, expression: &str) -> Result<EvalOutputs, Error> {
+        let (user_code, _) = CodeBlock::from_original_user_code(expression);
+        let variables: Vec<String> = self
+            .committed_state
+            .stored_variable_states
+            .keys()
+            .cloned()
+            .collect();
+        let user_code = CodeBlock::new()
+            .generated("let evcxr_value = (")
+            .add_all(user_code)
+            .generated(");");
+        let previews = [("text/plain".to_owned(), "evcxr_value".to_owned())];
+        self.run_readonly(user_code, &variables, &previews, None)
+    }
+
+    fn run_readonly(
+        &mut self,
+        user_code: CodeBlock,
+        variables: &[String],
+        previews: &[(String, String)],
+        max_chars: Option<usize>,
+    ) -> Result<EvalOutputs, Error> {
+        let _guard = self.interrupt_handle.begin_evaluation();
+        let mut state = self.state();
+        let code = state.readonly_code_to_compile(user_code, variables, previews, max_chars);
+        self.write_cargo_toml(&state)?;
+        let (so_file, _) = self.module.compile(&code, &state, &self.interrupt_handle)?;
+        let result =
+            self.run_and_capture_output(&mut state, &so_file, &mut EvalCallbacks::default());
+        match result {
+            Err(Error::SubprocessTerminated(_)) if self.interrupt_handle.is_interrupted() => {
+                self.restart_child_process()?;
+                Err(Error::Cancelled)
+            }
+            error @ Err(Error::SubprocessTerminated(_)) => {
+                self.restart_child_process()?;
+                error
+            }
+            Ok(outputs) if outputs.panicked => bail!("Evaluation panicked"),
+            result => result,
+        }
+    }
+
     pub fn defined_item_names(&self) -> impl Iterator<Item = &str> {
         self.committed_state
             .items_by_name
@@ -546,6 +805,12 @@ impl EvalContext {
         self.child_process.process_handle()
     }
 
+    /// Returns a handle that can be used to interrupt evaluation from another thread. Unlike
+    /// killing the process returned by `process_handle`, this also interrupts compilation.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt_handle.clone()
+    }
+
     fn restart_child_process(&mut self) -> Result<(), Error> {
         self.committed_state.variable_states.clear();
         self.committed_state.stored_variable_states.clear();
@@ -654,7 +919,9 @@ impl EvalContext {
         callbacks: &mut EvalCallbacks,
     ) -> Result<ExecutionArtifacts, Error> {
         let code = state.code_to_compile(user_code, compilation_mode);
-        let so_file = self.module.compile(&code, &state.config)?;
+        let (so_file, mut warnings) = self.module.compile(&code, state, &self.interrupt_handle)?;
+        // Warnings in code from earlier evaluations have already been reported.
+        warnings.retain(CompilationError::is_from_original_user_code);
 
         if compilation_mode == CompilationMode::NoCatchExpectError {
             // Uh-oh, caller was expecting an error, return OK and the caller can return the
@@ -663,15 +930,20 @@ impl EvalContext {
                 output: EvalOutputs::new(),
             });
         }
+        if state.config.warnings == Warnings::Deny && !warnings.is_empty() {
+            return Err(Error::CompilationErrors(warnings));
+        }
         phases.phase_complete("Final compile");
 
-        let output = self.run_and_capture_output(state, &so_file, callbacks)?;
+        let mut output = self.run_and_capture_output(state, &so_file, callbacks)?;
+        output.warnings = warnings;
         Ok(ExecutionArtifacts { output })
     }
 
     pub(crate) fn write_cargo_toml(&self, state: &ContextState) -> Result<()> {
         self.module.write_cargo_toml(state)?;
         self.module.write_config_toml(state)?;
+        self.module.write_c_sources(state)?;
         Ok(())
     }
 
@@ -732,12 +1004,26 @@ impl EvalContext {
         state: &mut ContextState,
         so_file: &SoFile,
         callbacks: &mut EvalCallbacks,
+    ) -> Result<EvalOutputs, Error> {
+        self.interrupt_handle.user_code_starting()?;
+        let result = self.load_and_run(state, so_file, callbacks);
+        self.interrupt_handle.user_code_finished();
+        result
+    }
+
+    fn load_and_run(
+        &mut self,
+        state: &mut ContextState,
+        so_file: &SoFile,
+        callbacks: &mut EvalCallbacks,
     ) -> Result<EvalOutputs, Error> {
         let mut output = EvalOutputs::new();
         // TODO: We should probably send an OsString not a String. Otherwise
         // things won't work if the path isn't UTF-8 - apparently that's a thing
         // on some platforms.
         let fn_name = state.current_user_fn_name();
+        let pid = self.child_process.pid();
+        let memory_before = sample_memory(pid);
         self.child_process.send(&format!(
             "LOAD_AND_RUN {} {}",
             so_file.path.to_string_lossy(),
@@ -780,28 +1066,24 @@ impl EvalContext {
                 lost_variables.push(variable_name.to_owned());
             } else if let Some(captures) = mime_output.captures(&line) {
                 let mime_type = captures[1].to_owned();
-                let mut content = String::new();
-                loop {
-                    let line = self.child_process.recv_line()?;
-                    if line == "EVCXR_END_CONTENT" {
-                        break;
-                    }
-                    if line == PANIC_NOTIFICATION {
-                        got_panic = true;
-                        break;
-                    }
-                    if !content.is_empty() {
-                        content.push('\n');
-                    }
-                    content.push_str(&line);
-                }
+                let (content, content_interrupted_by_panic) = self.read_content()?;
+                got_panic |= content_interrupted_by_panic;
                 output.content_by_mime_type.insert(mime_type, content);
             } else {
-                // Note, errors sending are ignored, since it just means the
-                // user of the library has dropped the Receiver.
-                let _ = self.stdout_sender.send(line);
+                self.forward_output_line(line);
             }
         }
+        if let (Some((rss_before, peak_rss_before)), Some((rss_after, peak_rss_after))) =
+            (memory_before, sample_memory(pid))
+        {
+            output.memory = Some(MemoryUsage {
+                rss_before,
+                rss_after,
+                peak_rss_before,
+                peak_rss_after,
+            });
+        }
+        output.panicked = got_panic;
         if got_panic {
             state
                 .variable_states
@@ -814,6 +1096,89 @@ impl EvalContext {
         Ok(output)
     }
 
+    /// Reads content following an EVCXR_BEGIN_CONTENT line. Also returns whether the content was cut
+    /// short by a panic.
+    fn read_content(&mut self) -> Result<(String, bool), Error> {
+        let mut content = String::new();
+        loop {
+            let line = self.child_process.recv_line()?;
+            if line == "EVCXR_END_CONTENT" {
+                return Ok((content, false));
+            }
+            if line == PANIC_NOTIFICATION {
+                return Ok((content, true));
+            }
+            if !content.is_empty() {
+                content.push('\n');
+            }
+            content.push_str(&line);
+        }
+    }
+
+    /// Forwards a line of output from the subprocess that doesn't have any special meaning to us.
+    fn forward_output_line(&self, line: String) {
+        // Note, errors sending are ignored, since it just means the user of the library has
+        // dropped the Receiver. A comm message follows anything that user code printed without a
+        // newline, so we look for the prefix anywhere in the line.
+        if let Some(position) = line.find(runtime::EVCXR_COMM_PREFIX) {
+            if position > 0 {
+                let _ = self.stdout_sender.send(line[..position].to_owned());
+            }
+            let message = &line[position + runtime::EVCXR_COMM_PREFIX.len()..];
+            let _ = self.comm_sender.send(message.to_owned());
+        } else {
+            let _ = self.stdout_sender.send(line);
+        }
+    }
+
+    /// Passes a comm message from the frontend to whatever handler user code registered via
+    /// evcxr_runtime. `message` is a JSON object in the same form as those received from
+    /// `EvalContextOutputs::comm`. Any messages sent by the handler are sent to
+    /// `EvalContextOutputs::comm` and any content that it displays is returned. If nothing is
+    /// registered to handle a comm_open, then the comm gets closed.
+    pub fn handle_comm_message(&mut self, message: &str) -> Result<EvalOutputs, Error> {
+        let _guard = self.interrupt_handle.begin_evaluation();
+        self.interrupt_handle.user_code_starting()?;
+        let result = self.run_comm_handler(message);
+        self.interrupt_handle.user_code_finished();
+        match result {
+            Err(Error::SubprocessTerminated(_)) if self.interrupt_handle.is_interrupted() => {
+                self.restart_child_process()?;
+                Err(Error::Cancelled)
+            }
+            error @ Err(Error::SubprocessTerminated(_)) => {
+                self.restart_child_process()?;
+                error
+            }
+            result => result,
+        }
+    }
+
+    fn run_comm_handler(&mut self, message: &str) -> Result<EvalOutputs, Error> {
+        // Newlines can only appear in JSON as whitespace, so it's safe to replace them in order to
+        // send the message as a single line.
+        self.child_process.send(&format!(
+            "{}{}",
+            runtime::EVCXR_COMM_PREFIX,
+            message.replace('\n', " ")
+        ))?;
+        let mut output = EvalOutputs::new();
+        loop {
+            let line = self.child_process.recv_line()?;
+            if line == runtime::EVCXR_EXECUTION_COMPLETE {
+                return Ok(output);
+            }
+            if let Some(mime_type) = line.strip_prefix("EVCXR_BEGIN_CONTENT ") {
+                let (content, _) = self.read_content()?;
+                output
+                    .content_by_mime_type
+                    .insert(mime_type.to_owned(), content);
+            } else {
+                self.forward_output_line(line);
+            }
+        }
+    }
+
     fn attempt_to_fix_error(
         &mut self,
         error: &CompilationError,
@@ -962,11 +1327,108 @@ impl PhaseDetailsBuilder {
     }
 }
 
+/// The memory usage of the process that runs user code, before and after running it. Sizes are in
+/// bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Resident set size before user code ran.
+    pub rss_before: u64,
+    pub rss_after: u64,
+    /// The largest resident set size since the process started, as of before user code ran.
+    pub peak_rss_before: u64,
+    pub peak_rss_after: u64,
+}
+
+impl MemoryUsage {
+    /// How much the resident set size changed. Negative if memory was released.
+    pub fn rss_delta(&self) -> i64 {
+        self.rss_after as i64 - self.rss_before as i64
+    }
+
+    /// How much the peak resident set size increased.
+    pub fn peak_rss_delta(&self) -> u64 {
+        self.peak_rss_after.saturating_sub(self.peak_rss_before)
+    }
+
+    /// Combines this usage with that of a subsequent run.
+    fn followed_by(self, later: MemoryUsage) -> MemoryUsage {
+        MemoryUsage {
+            rss_before: self.rss_before,
+            rss_after: later.rss_after,
+            peak_rss_before: self.peak_rss_before,
+            peak_rss_after: later.peak_rss_after,
+        }
+    }
+}
+
+impl std::fmt::Display for MemoryUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sign = if self.rss_delta() < 0 { "-" } else { "+" };
+        write!(
+            f,
+            "Memory: {sign}{} RSS (now {}), +{} peak RSS (now {})",
+            format_bytes(self.rss_delta().unsigned_abs()),
+            format_bytes(self.rss_after),
+            format_bytes(self.peak_rss_delta()),
+            format_bytes(self.peak_rss_after)
+        )
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 3] = ["KiB", "MiB", "GiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{value:.2} {}", UNITS[unit])
+}
+
+/// Returns the resident set size and peak resident set size of the process with the specified ID,
+/// if available.
+#[cfg(target_os = "linux")]
+fn sample_memory(pid: u32) -> Option<(u64, u64)> {
+    parse_proc_status(&std::fs::read_to_string(format!("/proc/{pid}/status")).ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sample_memory(_pid: u32) -> Option<(u64, u64)> {
+    None
+}
+
+/// Parses the resident set size and peak resident set size from the contents of
+/// /proc/<pid>/status.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn parse_proc_status(status: &str) -> Option<(u64, u64)> {
+    let field = |name: &str| -> Option<u64> {
+        let line = status.lines().find(|line| line.starts_with(name))?;
+        let kib = line[name.len()..].trim().strip_suffix("kB")?.trim();
+        Some(kib.parse::<u64>().ok()? * 1024)
+    };
+    Some((field("VmRSS:")?, field("VmHWM:")?))
+}
+
 #[derive(Default, Debug)]
 pub struct EvalOutputs {
     pub content_by_mime_type: HashMap<String, String>,
     pub timing: Option<Duration>,
     pub phases: Vec<PhaseDetails>,
+    /// Whether user code panicked. Panics are caught, so evaluation still succeeds.
+    pub panicked: bool,
+    /// Compiler warnings in the evaluated code. Empty if warnings have been turned off with
+    /// `:warnings off`.
+    pub warnings: Vec<CompilationError>,
+    /// Code that the frontend may offer to the user as their next input, e.g. a cell fixed by
+    /// `:fix`.
+    pub next_input: Option<String>,
+    /// How running the code affected memory usage. Only populated when enabled with `:memory` and
+    /// only available on Linux.
+    pub memory: Option<MemoryUsage>,
 }
 
 impl EvalOutputs {
@@ -975,6 +1437,10 @@ impl EvalOutputs {
             content_by_mime_type: HashMap::new(),
             timing: None,
             phases: Vec::new(),
+            panicked: false,
+            warnings: Vec::new(),
+            next_input: None,
+            memory: None,
         }
     }
 
@@ -1007,9 +1473,26 @@ impl EvalOutputs {
             (t1, t2) => t1.or(t2),
         };
         self.phases.append(&mut other.phases);
+        self.panicked |= other.panicked;
+        self.warnings.append(&mut other.warnings);
+        if other.next_input.is_some() {
+            self.next_input = other.next_input;
+        }
+        self.memory = match (self.memory, other.memory) {
+            (Some(m1), Some(m2)) => Some(m1.followed_by(m2)),
+            (m1, m2) => m1.or(m2),
+        };
     }
 }
 
+/// Information about a variable defined by user code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Variable {
+    pub name: String,
+    pub type_name: String,
+    pub is_mut: bool,
+}
+
 #[derive(Clone, Debug)]
 struct VariableState {
     type_name: String,
@@ -1066,7 +1549,21 @@ pub struct ContextState {
     async_mode: bool,
     allow_question_mark: bool,
     build_num: i32,
-    config: Config,
+    /// Native libraries that session code is linked against.
+    native_libs: Vec<NativeLib>,
+    /// Absolute path of the build script for session code, if any.
+    build_rs: Option<PathBuf>,
+    /// C and C++ source code to be compiled into session code, keyed by file name.
+    c_sources: BTreeMap<String, String>,
+    pub(crate) config: Config,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct NativeLib {
+    /// The argument to rustc's -l flag. e.g. "foo", or "static=foo".
+    pub(crate) name: String,
+    /// An absolute path to pass to rustc's -L flag.
+    pub(crate) search_path: Option<PathBuf>,
 }
 
 impl ContextState {
@@ -1082,6 +1579,9 @@ impl ContextState {
             async_mode: false,
             allow_question_mark: false,
             build_num: 0,
+            native_libs: Vec::new(),
+            build_rs: None,
+            c_sources: BTreeMap::new(),
             config,
         }
     }
@@ -1139,6 +1639,20 @@ impl ContextState {
         &self.config.linker
     }
 
+    pub fn set_warnings(&mut self, value: &str) -> Result<(), Error> {
+        self.config.warnings = match value {
+            "off" => Warnings::Off,
+            "on" => Warnings::On,
+            "deny" => Warnings::Deny,
+            _ => bail!("Unsupported warnings mode. Available options: on, off, deny"),
+        };
+        Ok(())
+    }
+
+    pub fn warnings(&self) -> &str {
+        self.config.warnings.name()
+    }
+
     pub fn preserve_vars_on_panic(&self) -> bool {
         self.config.preserve_vars_on_panic
     }
@@ -1186,8 +1700,14 @@ impl ContextState {
         self.config.display_types = display_types;
     }
 
-    pub fn set_toolchain(&mut self, value: &str) {
+    pub fn set_toolchain(&mut self, value: &str) -> Result<(), Error> {
+        // Since we invoke cargo and rustc directly rather than via rustup, we need to find the
+        // binaries for the new toolchain. If rustup isn't available, we fall back to whatever is on
+        // the path.
+        self.config.cargo_path = toolchain_binary_path(value, "cargo")?;
+        self.config.rustc_path = toolchain_binary_path(value, "rustc")?;
         self.config.toolchain = value.to_owned();
+        Ok(())
     }
 
     pub fn toolchain(&mut self) -> &str {
@@ -1208,6 +1728,75 @@ impl ContextState {
         Ok(())
     }
 
+    /// Links session code against the native library `name`, which is passed to rustc's -l flag,
+    /// so may be prefixed with a kind, e.g. "static=foo". If `search_path` is supplied, it's
+    /// searched for libraries at link time and, for dynamic libraries, when loading.
+    pub fn add_native_lib(&mut self, name: &str, search_path: Option<&Path>) -> Result<(), Error> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            bail!("Invalid library name '{}'", name);
+        }
+        let search_path = match search_path {
+            Some(path) => match path.canonicalize() {
+                Ok(path) => Some(path),
+                Err(error) => bail!("Invalid search path {:?}: {}", path, error),
+            },
+            None => None,
+        };
+        self.native_libs.retain(|lib| lib.name != name);
+        self.native_libs.push(NativeLib {
+            name: name.to_owned(),
+            search_path,
+        });
+        Ok(())
+    }
+
+    pub(crate) fn native_libs(&self) -> &[NativeLib] {
+        &self.native_libs
+    }
+
+    /// Sets the file to be used as the build script for session code.
+    pub fn set_build_rs(&mut self, path: &Path) -> Result<(), Error> {
+        if !self.c_sources.is_empty() {
+            bail!("A build script can't be used together with C or C++ code");
+        }
+        if !path.is_file() {
+            bail!("Build script {:?} doesn't exist", path);
+        }
+        match path.canonicalize() {
+            Ok(path) => self.build_rs = Some(path),
+            Err(error) => bail!("Invalid build script path {:?}: {}", path, error),
+        }
+        Ok(())
+    }
+
+    pub fn build_rs(&self) -> Option<&Path> {
+        self.build_rs.as_deref()
+    }
+
+    /// Sets C (if `extension` is "c") or C++ (if it's "cpp") source code to be compiled into
+    /// session code using the cc crate. `name` identifies the source, so that it can be replaced.
+    pub fn set_c_source(&mut self, name: &str, extension: &str, source: &str) -> Result<(), Error> {
+        if !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            bail!("Invalid name '{}'", name);
+        }
+        if extension != "c" && extension != "cpp" {
+            bail!("Unsupported source extension '{}'", extension);
+        }
+        if self.build_rs.is_some() {
+            bail!("C and C++ code can't be used together with a build script");
+        }
+        self.c_sources
+            .insert(format!("{name}.{extension}"), source.to_owned());
+        Ok(())
+    }
+
+    pub(crate) fn c_sources(&self) -> &BTreeMap<String, String> {
+        &self.c_sources
+    }
+
     /// Clears fields that aren't useful for inclusion in bug reports and which might give away
     /// things like usernames.
     pub(crate) fn clear_non_debug_relevant_fields(&mut self) {
@@ -1305,6 +1894,9 @@ impl ContextState {
             || (self.items_by_name != new_state.items_by_name
                 && !new_state.items_by_name.is_empty())
             || (self.config.sccache != new_state.config.sccache)
+            || self.native_libs != new_state.native_libs
+            || self.build_rs != new_state.build_rs
+            || self.c_sources != new_state.c_sources
     }
 
     pub(crate) fn format_cargo_deps(&self) -> String {
@@ -1384,6 +1976,87 @@ impl ContextState {
         code
     }
 
+    /// Returns code that runs `user_code` with shared references to `variables`, then, for each
+    /// of `previews`, which are pairs of a mime type and an expression, sends the value of the
+    /// expression formatted with Debug, truncated to `max_chars` if specified, as that mime type.
+    /// Nothing is taken from or put into the variable store.
+    fn readonly_code_to_compile(
+        &self,
+        user_code: CodeBlock,
+        variables: &[String],
+        previews: &[(String, String)],
+        max_chars: Option<usize>,
+    ) -> CodeBlock {
+        let mut code = CodeBlock::new()
+            .generated("#![allow(unused_imports, unused_mut, unused_variables, dead_code)]")
+            .add_all(self.attributes_code())
+            .add_all(self.items_code())
+            .generated("mod evcxr_internal_runtime {")
+            .generated(include_str!("evcxr_internal_runtime.rs"))
+            .generated("}")
+            .generated("#[no_mangle]")
+            .generated(format!(
+                "pub extern \"C\" fn {}(",
+                self.current_user_fn_name()
+            ))
+            .generated("evcxr_variable_store: *mut evcxr_internal_runtime::VariableStore)")
+            .generated("  -> *mut evcxr_internal_runtime::VariableStore {")
+            .generated("if std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {");
+        if !variables.is_empty() {
+            code = code.generated("let evcxr_variable_store = unsafe {&*evcxr_variable_store};");
+        }
+        for name in variables {
+            if let Some(variable_state) = self.stored_variable_states.get(name) {
+                code = code.generated(format!(
+                    "let {name} = evcxr_variable_store.variable_ref::<{}>(stringify!({name}));",
+                    variable_state.type_name
+                ));
+            }
+        }
+        // Uses autoref-based specialization to only call Debug for types that implement it. These
+        // definitions are in their own block so that they can't clash with names in `user_code`.
+        // A panic while formatting one preview shouldn't prevent the others from being sent, or be
+        // reported to the user, so each is caught separately with the panic hook silenced.
+        let take = max_chars.map_or(String::new(), |max| {
+            format!(".chars().take({max}).collect::<String>()")
+        });
+        let mut preview_code = String::new();
+        for (mime_type, expression) in previews {
+            preview_code.push_str(&format!(
+                r#"
+                let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {{
+                    if let Some(preview) = (&EvcxrPreview(&{expression})).evcxr_preview() {{
+                        println!("EVCXR_BEGIN_CONTENT {mime_type}\n{{}}\nEVCXR_END_CONTENT", preview);
+                    }}
+                }}));"#
+            ));
+        }
+        code.add_all(user_code)
+            .generated("{")
+            .generated(format!(
+                r#"
+                struct EvcxrPreview<'a, T>(&'a T);
+                trait EvcxrPreviewDebug {{ fn evcxr_preview(&self) -> Option<String>; }}
+                impl<'a, T: std::fmt::Debug> EvcxrPreviewDebug for EvcxrPreview<'a, T> {{
+                    fn evcxr_preview(&self) -> Option<String> {{ Some(format!("{{:?}}", self.0){take}) }}
+                }}
+                trait EvcxrPreviewFallback {{ fn evcxr_preview(&self) -> Option<String>; }}
+                impl<'a, T> EvcxrPreviewFallback for &EvcxrPreview<'a, T> {{
+                    fn evcxr_preview(&self) -> Option<String> {{ None }}
+                }}
+                let evcxr_panic_hook = std::panic::take_hook();
+                std::panic::set_hook(Box::new(|_| {{}}));
+                {preview_code}
+                std::panic::set_hook(evcxr_panic_hook);
+                }}
+                }})).is_err() {{
+                    println!("{PANIC_NOTIFICATION}");
+                }}
+                evcxr_variable_store
+                }}"#
+            ))
+    }
+
     fn items_code(&self) -> CodeBlock {
         let mut code = CodeBlock::new().add_all(self.get_imports());
         for item in self.items_by_name.values().chain(self.unnamed_items.iter()) {
@@ -1835,15 +2508,7 @@ impl ContextState {
 // compile. This reduces eval time for a trivial bit of code from about 140ms to
 // 109ms.
 fn rustup_cargo_path() -> Option<String> {
-    let output = Command::new("rustup")
-        .arg("which")
-        .arg("cargo")
-        .output()
-        .ok()?;
-    if !output.status.success() {
-        return None;
-    }
-    Some(std::str::from_utf8(&output.stdout).ok()?.trim().to_owned())
+    rustup_which("", "cargo")
 }
 
 fn default_cargo_path() -> String {
@@ -1853,19 +2518,49 @@ fn default_cargo_path() -> String {
 // Similar to the above, this avoids cargo invoking rustup, cutting the eval
 // time for a trivial bit of code to about 75ms.
 fn rustup_rustc_path() -> Option<String> {
-    let output = Command::new("rustup")
-        .arg("which")
-        .arg("rustc")
-        .output()
-        .ok()?;
+    rustup_which("", "rustc")
+}
+
+fn default_rustc_path() -> String {
+    rustup_rustc_path().unwrap_or_else(|| "rustc".to_owned())
+}
+
+// Returns the path to `binary` in `toolchain`, or just `binary` if rustup isn't
+// available. Fails if rustup is available, but can't find the toolchain.
+fn toolchain_binary_path(toolchain: &str, binary: &str) -> Result<String, Error> {
+    let Some(output) = rustup_which_output(toolchain, binary) else {
+        return Ok(binary.to_owned());
+    };
+    if !output.status.success() {
+        // Only the first line, since rustup may follow it with a backtrace.
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "Failed to find {} for toolchain `{}`: {}",
+            binary,
+            toolchain,
+            stderr.lines().next().unwrap_or_default().trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+fn rustup_which(toolchain: &str, binary: &str) -> Option<String> {
+    let output = rustup_which_output(toolchain, binary)?;
     if !output.status.success() {
         return None;
     }
     Some(std::str::from_utf8(&output.stdout).ok()?.trim().to_owned())
 }
 
-fn default_rustc_path() -> String {
-    rustup_rustc_path().unwrap_or_else(|| "rustc".to_owned())
+// Runs `rustup which` for `binary` in `toolchain`, or the current toolchain if
+// it's empty. Returns None if rustup couldn't be run.
+fn rustup_which_output(toolchain: &str, binary: &str) -> Option<std::process::Output> {
+    let mut command = Command::new("rustup");
+    command.arg("which");
+    if !toolchain.is_empty() {
+        command.arg("--toolchain").arg(toolchain);
+    }
+    command.arg(binary).output().ok()
 }
 
 fn replace_reserved_words_in_type(ty: &str) -> String {
@@ -1883,6 +2578,28 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_parse_proc_status() {
+        let status =
+            "Name:\tevcxr\nVmPeak:\t  20000 kB\nVmHWM:\t    3000 kB\nVmRSS:\t    2048 kB\n";
+        assert_eq!(parse_proc_status(status), Some((2048 * 1024, 3000 * 1024)));
+        assert_eq!(parse_proc_status("Name:\tevcxr\n"), None);
+    }
+
+    #[test]
+    fn test_format_memory_usage() {
+        let usage = MemoryUsage {
+            rss_before: 2 * 1024 * 1024,
+            rss_after: 1024 * 1024,
+            peak_rss_before: 3 * 1024 * 1024,
+            peak_rss_after: 3 * 1024 * 1024 + 512,
+        };
+        assert_eq!(
+            usage.to_string(),
+            "Memory: -1.00 MiB RSS (now 1.00 MiB), +512 B peak RSS (now 3.00 MiB)"
+        );
+    }
+
     #[test]
     fn test_replace_reserved_words_in_type() {
         use super::replace_reserved_words_in_type as repl;
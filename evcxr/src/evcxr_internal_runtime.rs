@@ -38,6 +38,16 @@ impl VariableStore {
         true
     }
 
+    /// Returns a reference to a variable without taking it from the store. Panics if the variable
+    /// is missing or has a different type.
+    pub fn variable_ref<T: 'static>(&self, name: &str) -> &T {
+        match self.variables.get(name).map(|v| v.downcast_ref()) {
+            Some(Some(value)) => value,
+            Some(None) => panic!("Variable changed type"),
+            None => panic!("Variable '{name}' has gone missing"),
+        }
+    }
+
     pub fn take_variable<T: 'static>(&mut self, name: &str) -> T {
         match self.variables.remove(name) {
             Some(v) => {
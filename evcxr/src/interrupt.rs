@@ -0,0 +1,160 @@
+// Copyright 2023 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::errors::bail;
+use crate::errors::Error;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// A handle that can be used from another thread to interrupt whatever evaluation is currently in
+/// progress. If cargo is running, it (together with any rustc processes that it started) gets
+/// killed. If user code is running, the subprocess gets killed and will be restarted, losing any
+/// variables, but preserving other state. Interrupts received when nothing is being evaluated are
+/// ignored.
+#[derive(Clone)]
+pub struct InterruptHandle {
+    state: Arc<Mutex<InterruptState>>,
+    process_handle: Arc<Mutex<std::process::Child>>,
+}
+
+#[derive(Default)]
+struct InterruptState {
+    /// How many (possibly nested) evaluations are in progress.
+    evaluation_depth: usize,
+    /// Whether an interrupt has been requested since the outermost evaluation started.
+    interrupted: bool,
+    /// Process ID of cargo, if it's currently running on behalf of an evaluation.
+    cargo_pid: Option<u32>,
+    /// Whether user code is currently running in the subprocess.
+    running_user_code: bool,
+}
+
+impl InterruptHandle {
+    pub(crate) fn new(process_handle: Arc<Mutex<std::process::Child>>) -> InterruptHandle {
+        InterruptHandle {
+            state: Arc::new(Mutex::new(InterruptState::default())),
+            process_handle,
+        }
+    }
+
+    /// Interrupts the current evaluation, if any. The evaluation will then fail with
+    /// `Error::Cancelled`.
+    pub fn interrupt(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.evaluation_depth == 0 {
+            return;
+        }
+        state.interrupted = true;
+        if let Some(pid) = state.cargo_pid {
+            kill_process_tree(pid);
+        } else if state.running_user_code {
+            let _ = self.process_handle.lock().unwrap().kill();
+        }
+    }
+
+    /// Marks the start of an evaluation. The evaluation is considered to be in progress until the
+    /// returned guard is dropped.
+    pub(crate) fn begin_evaluation(&self) -> EvaluationGuard {
+        let mut state = self.state.lock().unwrap();
+        if state.evaluation_depth == 0 {
+            state.interrupted = false;
+        }
+        state.evaluation_depth += 1;
+        EvaluationGuard {
+            state: Arc::clone(&self.state),
+        }
+    }
+
+    pub(crate) fn is_interrupted(&self) -> bool {
+        self.state.lock().unwrap().interrupted
+    }
+
+    /// Marks user code as running in the subprocess. Fails if we've already been interrupted, in
+    /// which case the user code shouldn't be started.
+    pub(crate) fn user_code_starting(&self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.interrupted {
+            bail!(Error::Cancelled);
+        }
+        state.running_user_code = true;
+        Ok(())
+    }
+
+    pub(crate) fn user_code_finished(&self) {
+        self.state.lock().unwrap().running_user_code = false;
+    }
+
+    /// Runs `command` to completion, capturing its output, the same as `Command::output`, except
+    /// that it can be interrupted.
+    pub(crate) fn run_command(&self, mut command: Command) -> Result<std::process::Output, Error> {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        isolate_process_group(&mut command);
+        let child = {
+            let mut state = self.state.lock().unwrap();
+            if state.interrupted {
+                bail!(Error::Cancelled);
+            }
+            let child = match command.spawn() {
+                Ok(child) => child,
+                Err(error) => bail!("Failed to run '{:?}': {}", command, error),
+            };
+            state.cargo_pid = Some(child.id());
+            child
+        };
+        let output = child.wait_with_output();
+        let mut state = self.state.lock().unwrap();
+        state.cargo_pid = None;
+        if state.interrupted {
+            bail!(Error::Cancelled);
+        }
+        Ok(output?)
+    }
+}
+
+/// Returned by `InterruptHandle::begin_evaluation`.
+pub(crate) struct EvaluationGuard {
+    state: Arc<Mutex<InterruptState>>,
+}
+
+impl Drop for EvaluationGuard {
+    fn drop(&mut self) {
+        self.state.lock().unwrap().evaluation_depth -= 1;
+    }
+}
+
+// We put cargo in its own process group so that when we kill it, we can kill any rustc processes
+// that it has started at the same time. Otherwise they'd keep running and keep our pipes open.
+#[cfg(unix)]
+fn isolate_process_group(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+}
+
+#[cfg(not(unix))]
+fn isolate_process_group(_command: &mut Command) {}
+
+#[cfg(unix)]
+fn kill_process_tree(pid: u32) {
+    unsafe {
+        libc::kill(-(pid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+#[cfg(windows)]
+fn kill_process_tree(pid: u32) {
+    let _ = Command::new("taskkill")
+        .arg("/F")
+        .arg("/T")
+        .arg("/PID")
+        .arg(pid.to_string())
+        .output();
+}
+
+#[cfg(not(any(unix, windows)))]
+fn kill_process_tree(_pid: u32) {}
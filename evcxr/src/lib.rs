@@ -11,22 +11,32 @@ extern crate sig;
 
 #[macro_use]
 mod errors;
+mod async_context;
+mod bench;
 mod cargo_metadata;
 mod child_process;
 mod code_block;
 mod command_context;
 mod crash_guard;
 mod crate_config;
+mod emit;
 mod eval_context;
 #[allow(dead_code)]
 mod evcxr_internal_runtime;
+mod interrupt;
 mod item;
 mod module;
 mod runtime;
 mod rust_analyzer;
+mod rustfmt;
 mod statement_splitter;
+mod test_runner;
+mod transcript;
 mod use_trees;
 
+pub use crate::async_context::AsyncCommandContext;
+pub use crate::async_context::CancelHandle;
+pub use crate::async_context::Evaluation;
 pub use crate::command_context::CommandContext;
 pub use crate::errors::Error;
 pub use crate::errors::{CompilationError, Theme};
@@ -34,7 +44,14 @@ pub use crate::eval_context::EvalCallbacks;
 pub use crate::eval_context::EvalContext;
 pub use crate::eval_context::EvalContextOutputs;
 pub use crate::eval_context::EvalOutputs;
+pub use crate::eval_context::MemoryUsage;
+pub use crate::eval_context::PhaseDetails;
+pub use crate::eval_context::Variable;
+pub use crate::interrupt::InterruptHandle;
 pub use crate::runtime::runtime_hook;
+pub use crate::transcript::run_transcript;
+pub use crate::transcript::TranscriptFailure;
+pub use crate::transcript::TranscriptMismatch;
 pub use rust_analyzer::Completions;
 
 /// Return the directory that evcxr tools should use for their configuration.
@@ -7,11 +7,15 @@
 // copied, modified, or distributed except according to those terms.
 
 use crate::code_block::CodeBlock;
+use crate::crate_config::escape_toml_string;
+use crate::emit::EmitKind;
 use crate::errors::bail;
 use crate::errors::CompilationError;
 use crate::errors::Error;
 use crate::eval_context::Config;
 use crate::eval_context::ContextState;
+use crate::eval_context::Warnings;
+use crate::interrupt::InterruptHandle;
 use once_cell::sync::OnceCell;
 use regex::Regex;
 use std::fs;
@@ -84,13 +88,35 @@ fn rename_or_copy_so_file(src: &Path, dest: &Path) -> Result<(), Error> {
     Ok(())
 }
 
+/// The cargo subcommand that `Module::check` runs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CheckCommand {
+    Check,
+    Clippy,
+}
+
+impl CheckCommand {
+    fn name(self) -> &'static str {
+        match self {
+            CheckCommand::Check => "check",
+            CheckCommand::Clippy => "clippy",
+        }
+    }
+}
+
 pub(crate) struct Module {
     pub(crate) tmpdir: PathBuf,
     build_num: i32,
     target: String,
 }
 
-const CRATE_NAME: &str = "ctx";
+pub(crate) const CRATE_NAME: &str = "ctx";
+/// The target directory, relative to the crate, used by `Module::emit` and `Module::unpretty`.
+const EMIT_TARGET_DIR: &str = "target/emit";
+/// The directory, relative to the crate, to which sources from :c and :cpp cells are written.
+const C_SRC_DIR: &str = "c_src";
+/// The build script, relative to the crate, that compiles sources from :c and :cpp cells.
+const C_BUILD_SCRIPT: &str = "build_c.rs";
 
 impl Module {
     pub(crate) fn new(tmpdir: PathBuf) -> Result<Module, Error> {
@@ -151,26 +177,38 @@ impl Module {
         &mut self,
         code_block: &CodeBlock,
         config: &Config,
+        command: CheckCommand,
     ) -> Result<Vec<CompilationError>, Error> {
         self.write_code(code_block)?;
         let output = config
-            .cargo_command("check")
+            .cargo_command(command.name())
             .arg("--message-format=json")
             .output();
 
         let cargo_output = match output {
             Ok(out) => out,
-            Err(err) => bail!("Error running 'cargo check': {}", err),
+            Err(err) => bail!("Error running 'cargo {}': {}", command.name(), err),
         };
         let (errors, _non_json_error) = errors_from_cargo_output(&cargo_output, code_block);
+        // If clippy isn't installed, we'd otherwise just report that there were no lints.
+        if command == CheckCommand::Clippy && errors.is_empty() && !cargo_output.status.success() {
+            bail!(
+                "Error running 'cargo clippy': {}",
+                String::from_utf8_lossy(&cargo_output.stderr).trim()
+            );
+        }
         Ok(errors)
     }
 
+    /// Compiles `code_block`, returning the compiled file and any warnings, unless warnings are
+    /// turned off.
     pub(crate) fn compile(
         &mut self,
         code_block: &CodeBlock,
-        config: &Config,
-    ) -> Result<SoFile, Error> {
+        state: &ContextState,
+        interrupt_handle: &InterruptHandle,
+    ) -> Result<(SoFile, Vec<CompilationError>), Error> {
+        let config = &state.config;
         let mut command = config.cargo_command("rustc");
         if config.time_passes && config.toolchain != "nightly" {
             bail!("time_passes option requires nightly compiler");
@@ -185,11 +223,7 @@ impl Module {
             .arg("prefer-dynamic")
             .env("CARGO_TARGET_DIR", "target")
             .env("RUSTC", &config.rustc_path);
-        if config.linker == "lld" {
-            command
-                .arg("-C")
-                .arg(format!("link-arg=-fuse-ld={}", config.linker));
-        }
+        add_link_args(&mut command, state);
         if let Some(sccache) = &config.sccache {
             command.env("RUSTC_WRAPPER", sccache);
         }
@@ -197,11 +231,16 @@ impl Module {
             command.arg("-Ztime-passes");
         }
         self.write_code(code_block)?;
-        let cargo_output = run_cargo(command, code_block)?;
+        let cargo_output = run_cargo(command, code_block, interrupt_handle)?;
         if config.time_passes {
             let output = String::from_utf8_lossy(&cargo_output.stderr);
             eprintln!("{output}");
         }
+        let mut warnings = Vec::new();
+        if config.warnings != Warnings::Off {
+            warnings = errors_from_cargo_output(&cargo_output, code_block).0;
+            warnings.retain(|warning| warning.level() == "warning");
+        }
         self.build_num += 1;
         let copied_so_file = self
             .deps_dir()
@@ -215,9 +254,137 @@ impl Module {
         // a loaded dll gets locked, so we couldn't even compile a second time
         // if we didn't load a different file.
         rename_or_copy_so_file(&self.so_path(), &copied_so_file)?;
-        Ok(SoFile {
-            path: copied_so_file,
-        })
+        Ok((
+            SoFile {
+                path: copied_so_file,
+            },
+            warnings,
+        ))
+    }
+
+    /// Compiles `code_block`, returning the assembly or LLVM IR that rustc emits for it.
+    pub(crate) fn emit(
+        &mut self,
+        code_block: &CodeBlock,
+        state: &ContextState,
+        kind: EmitKind,
+        interrupt_handle: &InterruptHandle,
+    ) -> Result<String, Error> {
+        let config = &state.config;
+        let mut command = config.cargo_command("rustc");
+        // Cargo doesn't take extra rustc arguments into account when deciding whether it needs to
+        // rebuild, so we use a separate target directory to avoid reusing normal build outputs.
+        // Functions that aren't called would normally be omitted, so we ask for them to be kept.
+        command
+            .arg("--target")
+            .arg(&self.target)
+            .arg("--message-format=json")
+            .arg("--")
+            .arg("-C")
+            .arg("prefer-dynamic")
+            .arg("-C")
+            .arg("link-dead-code")
+            .arg("-C")
+            .arg("codegen-units=1")
+            .arg("--emit")
+            .arg(kind.rustc_emit())
+            .env("CARGO_TARGET_DIR", EMIT_TARGET_DIR)
+            .env("RUSTC", &config.rustc_path);
+        self.write_code(code_block)?;
+        run_cargo(command, code_block, interrupt_handle)?;
+        let path = self
+            .tmpdir
+            .join(EMIT_TARGET_DIR)
+            .join(&self.target)
+            .join("debug")
+            .join("deps")
+            .join(format!("{CRATE_NAME}.{}", kind.file_extension()));
+        match fs::read_to_string(&path) {
+            Ok(emitted) => Ok(emitted),
+            Err(error) => bail!("Failed to read {}: {}", path.display(), error),
+        }
+    }
+
+    /// Compiles `code_block` with `-Zunpretty=<mode>`, returning what rustc prints. This requires
+    /// a nightly toolchain.
+    pub(crate) fn unpretty(
+        &mut self,
+        code_block: &CodeBlock,
+        state: &ContextState,
+        mode: &str,
+        interrupt_handle: &InterruptHandle,
+    ) -> Result<String, Error> {
+        let config = &state.config;
+        if !config.toolchain.starts_with("nightly") {
+            bail!("This command requires a nightly compiler. Try `:toolchain nightly`");
+        }
+        let mut command = config.cargo_command("rustc");
+        command
+            .arg("--target")
+            .arg(&self.target)
+            .arg("--message-format=json")
+            .arg("--")
+            .arg(format!("-Zunpretty={mode}"))
+            .env("CARGO_TARGET_DIR", EMIT_TARGET_DIR)
+            .env("RUSTC", &config.rustc_path);
+        self.write_code(code_block)?;
+        let cargo_output = run_cargo(command, code_block, interrupt_handle)?;
+        // What rustc prints is followed by cargo's JSON messages.
+        Ok(String::from_utf8_lossy(&cargo_output.stdout)
+            .lines()
+            .filter(|line| !line.starts_with("{\"reason\":"))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    }
+
+    /// Compiles `code_block` with the standard test harness, then runs the tests whose names
+    /// contain `filter`, returning what the harness prints.
+    pub(crate) fn test(
+        &mut self,
+        code_block: &CodeBlock,
+        state: &ContextState,
+        filter: Option<&str>,
+        interrupt_handle: &InterruptHandle,
+    ) -> Result<String, Error> {
+        let config = &state.config;
+        let mut command = config.cargo_command("rustc");
+        // We build with `cargo rustc` rather than `cargo test` so that we can link the same way
+        // that `compile` does. We share the target directory with normal builds so that
+        // dependencies don't need to be built again.
+        command
+            .arg("--lib")
+            .arg("--profile")
+            .arg("test")
+            .arg("--target")
+            .arg(&self.target)
+            .arg("--message-format=json")
+            .arg("--")
+            .env("CARGO_TARGET_DIR", "target")
+            .env("RUSTC", &config.rustc_path);
+        add_link_args(&mut command, state);
+        if let Some(sccache) = &config.sccache {
+            command.env("RUSTC_WRAPPER", sccache);
+        }
+        self.write_code(code_block)?;
+        let cargo_output = run_cargo(command, code_block, interrupt_handle)?;
+        let Some(executable) = String::from_utf8_lossy(&cargo_output.stdout)
+            .lines()
+            .filter_map(|line| json::parse(line).ok())
+            .find_map(|message| message["executable"].as_str().map(PathBuf::from))
+        else {
+            bail!("Cargo didn't report building a test executable");
+        };
+        let mut command = Command::new(executable);
+        command.arg("--show-output");
+        if let Some(filter) = filter {
+            command.arg(filter);
+        }
+        let output = match interrupt_handle.run_command(command) {
+            Ok(output) => output,
+            Err(Error::Cancelled) => return Err(Error::Cancelled),
+            Err(error) => bail!("Error running tests: {}", error),
+        };
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
     }
 
     fn write_code(&self, code_block: &CodeBlock) -> Result<(), Error> {
@@ -245,14 +412,25 @@ impl Module {
     }
 
     fn get_cargo_toml_contents(&self, state: &ContextState) -> String {
-        let crate_imports = state.format_cargo_deps();
+        let mut crate_imports = state.format_cargo_deps();
+        let build = if let Some(path) = state.build_rs() {
+            format!(
+                "build = \"{}\"\n",
+                escape_toml_string(&path.to_string_lossy())
+            )
+        } else if !state.c_sources().is_empty() {
+            crate_imports.push_str("\n[build-dependencies]\ncc = \"1.0\"\n");
+            format!("build = \"{C_BUILD_SCRIPT}\"\n")
+        } else {
+            String::new()
+        };
         format!(
             r#"
 [package]
 name = "{}"
 version = "1.0.0"
 edition = "2021"
-
+{}
 [lib]
 crate-type = ["cdylib"]
 path = "src/lib.rs"
@@ -273,11 +451,56 @@ overflow-checks = true
 {}
 "#,
             CRATE_NAME,
+            build,
             state.opt_level(),
             crate_imports
         )
     }
 
+    /// Writes the C and C++ sources from :c and :cpp cells, together with a build script that
+    /// compiles them. Should be called before compile.
+    pub(crate) fn write_c_sources(&self, state: &ContextState) -> Result<(), Error> {
+        if state.c_sources().is_empty() {
+            return Ok(());
+        }
+        let c_src_dir = self.crate_dir().join(C_SRC_DIR);
+        let mut c_files = Vec::new();
+        let mut cpp_files = Vec::new();
+        for (file_name, source) in state.c_sources() {
+            write_file(&c_src_dir, file_name, source)?;
+            let path = format!("{C_SRC_DIR}/{file_name}");
+            if file_name.ends_with(".cpp") {
+                cpp_files.push(path);
+            } else {
+                c_files.push(path);
+            }
+        }
+        let mut build_rs = String::from("fn main() {\n");
+        for (files, cpp, lib_name) in [(c_files, false, "evcxr_c"), (cpp_files, true, "evcxr_cpp")]
+        {
+            if files.is_empty() {
+                continue;
+            }
+            build_rs.push_str("    cc::Build::new()\n");
+            if cpp {
+                build_rs.push_str("        .cpp(true)\n");
+            }
+            for file in &files {
+                build_rs.push_str(&format!("        .file({file:?})\n"));
+            }
+            build_rs.push_str(&format!("        .compile({lib_name:?});\n"));
+            // The cc crate emits rerun-if-env-changed, which disables cargo's default of rerunning
+            // if anything in the package changes, so we need to say which files we depend on.
+            for file in &files {
+                build_rs.push_str(&format!(
+                    "    println!(\"cargo:rerun-if-changed={{}}\", {file:?});\n"
+                ));
+            }
+        }
+        build_rs.push_str("}\n");
+        write_file(self.crate_dir(), C_BUILD_SCRIPT, &build_rs)
+    }
+
     // Pass offline mode to cargo through .cargo/config.toml
     fn get_config_toml_contents(&self, state: &ContextState) -> String {
         format!(
@@ -289,12 +512,37 @@ offline = {}
         )
     }
 }
+/// Adds the rustc arguments needed to use the configured linker and to link against native
+/// libraries added with `:link`. `command` should already have had `--` added.
+fn add_link_args(command: &mut Command, state: &ContextState) {
+    let config = &state.config;
+    if config.linker == "lld" {
+        command
+            .arg("-C")
+            .arg(format!("link-arg=-fuse-ld={}", config.linker));
+    }
+    for lib in state.native_libs() {
+        if let Some(search_path) = &lib.search_path {
+            command.arg("-L").arg(search_path);
+            // So that dynamic libraries can be found when we load the compiled code.
+            if !cfg!(windows) {
+                command
+                    .arg("-C")
+                    .arg(format!("link-arg=-Wl,-rpath,{}", search_path.display()));
+            }
+        }
+        command.arg("-l").arg(&lib.name);
+    }
+}
+
 fn run_cargo(
-    mut command: std::process::Command,
+    command: std::process::Command,
     code_block: &CodeBlock,
+    interrupt_handle: &InterruptHandle,
 ) -> Result<std::process::Output, Error> {
-    let cargo_output = match command.output() {
+    let cargo_output = match interrupt_handle.run_command(command) {
         Ok(out) => out,
+        Err(Error::Cancelled) => return Err(Error::Cancelled),
         Err(err) => bail!("Error running 'cargo rustc': {}", err),
     };
     if cargo_output.status.success() {
@@ -346,7 +594,26 @@ fn errors_from_cargo_output(
                     None
                 })
         })
-        .collect();
+        .collect::<Vec<_>>();
+    if errors.is_empty()
+        && non_json_error.is_none()
+        && stderr.contains("failed to run custom build command")
+    {
+        // Output from C compilers run by build scripts is reported by cargo as warnings, which we
+        // extract, so that the user doesn't have to find it amongst everything else.
+        let warning_prefix = format!("warning: {CRATE_NAME}@");
+        let output: Vec<&str> = stderr
+            .lines()
+            .filter_map(|line| line.strip_prefix(&warning_prefix))
+            .map(|rest| {
+                rest.split_once(": ")
+                    .map_or(rest, |(_version, message)| message)
+            })
+            .collect();
+        if !output.is_empty() {
+            non_json_error = Some(format!("Build script failed:\n{}", output.join("\n")));
+        }
+    }
     (errors, non_json_error)
 }
 
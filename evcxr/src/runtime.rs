@@ -16,6 +16,37 @@ use std::{self};
 
 pub(crate) const EVCXR_IS_RUNTIME_VAR: &str = "EVCXR_IS_RUNTIME";
 pub(crate) const EVCXR_EXECUTION_COMPLETE: &str = "EVCXR_EXECUTION_COMPLETE";
+/// Prefixes lines that pass comm messages between the kernel and user code. The following need to
+/// match the values in evcxr_runtime.
+pub(crate) const EVCXR_COMM_PREFIX: &str = "EVCXR_COMM ";
+/// Function exported by each shared object that links evcxr_runtime, which passes comm messages to
+/// handlers registered by user code in that shared object.
+const EVCXR_COMM_DISPATCH_SYMBOL: &[u8] = b"evcxr_runtime_comm_dispatch_v1";
+
+type CommDispatch = unsafe extern "C" fn(*const RawCommMessage) -> bool;
+
+#[repr(C)]
+struct RawStr {
+    ptr: *const u8,
+    len: usize,
+}
+
+#[repr(C)]
+struct RawCommMessage {
+    message_type: RawStr,
+    comm_id: RawStr,
+    target_name: RawStr,
+    data: RawStr,
+}
+
+impl RawStr {
+    fn new(s: &str) -> RawStr {
+        RawStr {
+            ptr: s.as_ptr(),
+            len: s.len(),
+        }
+    }
+}
 
 /// Binaries can call this just after staring. If we detect that we're actually
 /// running as a subprocess, control will not return.
@@ -27,6 +58,8 @@ pub fn runtime_hook() {
 
 struct Runtime {
     shared_objects: Vec<libloading::Library>,
+    /// Comm dispatch functions of loaded shared objects, in the order they were loaded.
+    comm_dispatchers: Vec<CommDispatch>,
     variable_store_ptr: *mut std::os::raw::c_void,
     // Our variable store is permitted to contain non-Send types (e.g. Rc), therefore we need to be
     // non-Send as well.
@@ -37,6 +70,7 @@ impl Runtime {
     fn new() -> Runtime {
         Runtime {
             shared_objects: Vec::new(),
+            comm_dispatchers: Vec::new(),
             variable_store_ptr: std::ptr::null_mut(),
             _phantom_rc: PhantomData,
         }
@@ -65,6 +99,9 @@ impl Runtime {
             LOAD_AND_RUN.get_or_init(|| Regex::new("LOAD_AND_RUN ([^ ]+) ([^ ]+)").unwrap());
         if let Some(captures) = load_and_run.captures(line) {
             self.load_and_run(&captures[1], &captures[2])
+        } else if let Some(message) = line.strip_prefix(EVCXR_COMM_PREFIX) {
+            self.dispatch_comm_message(message);
+            Ok(())
         } else {
             bail!("Unrecognised line: {}", line);
         }
@@ -79,10 +116,56 @@ impl Runtime {
             self.variable_store_ptr = user_fn(self.variable_store_ptr);
         }
         println!("{EVCXR_EXECUTION_COMPLETE}");
+        // Safety: evcxr_runtime exports this symbol with this signature. Shared objects are never
+        // unloaded, so the function will remain valid.
+        if let Ok(dispatch) =
+            unsafe { shared_object.get::<CommDispatch>(EVCXR_COMM_DISPATCH_SYMBOL) }
+        {
+            self.comm_dispatchers.push(*dispatch);
+        }
         self.shared_objects.push(shared_object);
         Ok(())
     }
 
+    /// Passes a comm message to user code. Each shared object has its own copy of evcxr_runtime
+    /// containing the handlers registered by that cell. A comm_open goes to the most recent cell
+    /// with a handler for the target, so that rerunning a cell replaces its handler. Other messages
+    /// go to every cell, since handlers for a comm may have been registered by several.
+    fn dispatch_comm_message(&mut self, message: &str) {
+        if let Ok(message) = json::parse(message) {
+            let message_type = message["type"].as_str().unwrap_or("");
+            let comm_id = message["comm_id"].as_str().unwrap_or("");
+            let data = message["data"].dump();
+            let raw_message = RawCommMessage {
+                message_type: RawStr::new(message_type),
+                comm_id: RawStr::new(comm_id),
+                target_name: RawStr::new(message["target_name"].as_str().unwrap_or("")),
+                data: RawStr::new(&data),
+            };
+            // Safety: The message and the strings it points to outlive the calls.
+            if message_type == "open" {
+                let handled = self
+                    .comm_dispatchers
+                    .iter()
+                    .rev()
+                    .any(|dispatch| unsafe { dispatch(&raw_message) });
+                if !handled {
+                    // Nothing can handle the comm, so close it.
+                    let close = json::object! {
+                        "type" => "close",
+                        "comm_id" => comm_id,
+                    };
+                    println!("{EVCXR_COMM_PREFIX}{}", close.dump());
+                }
+            } else {
+                for dispatch in &self.comm_dispatchers {
+                    unsafe { dispatch(&raw_message) };
+                }
+            }
+        }
+        println!("{EVCXR_EXECUTION_COMPLETE}");
+    }
+
     #[cfg(all(unix, not(target_os = "freebsd")))]
     pub fn install_crash_handlers(&self) {
         use backtrace::Backtrace;
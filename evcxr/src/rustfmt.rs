@@ -0,0 +1,110 @@
+// Copyright 2023 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Formatting of cells with rustfmt.
+
+use crate::code_block::CodeBlock;
+use crate::code_block::CodeKind;
+use crate::errors::bail;
+use crate::errors::Error;
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+
+/// Cells can contain statements and a trailing expression, which aren't valid at the top level of
+/// a file, so we format the code as the body of this function.
+const WRAPPER_START: &str = "fn evcxr_rustfmt_wrapper() {\n";
+const WRAPPER_END: &str = "}\n";
+const INDENT: &str = "    ";
+
+/// Formats the Rust code in `cell` with the rustfmt binary at `rustfmt_path`. Any commands and
+/// comments before the Rust code are left as they are.
+pub(crate) fn format_cell(rustfmt_path: &str, cell: &str) -> Result<String, Error> {
+    let (code_block, _) = CodeBlock::from_original_user_code(cell);
+    let Some(code_start) = code_block
+        .segments
+        .iter()
+        .find_map(|segment| match &segment.kind {
+            CodeKind::OriginalUserCode(meta) => Some(meta.start_byte),
+            _ => None,
+        })
+    else {
+        return Ok(cell.to_owned());
+    };
+    let line_start = cell[..code_start].rfind('\n').map_or(0, |i| i + 1);
+    let mut formatted = cell[..line_start].to_owned();
+    formatted.push_str(&format_code(rustfmt_path, &cell[line_start..])?);
+    if !cell.ends_with('\n') && formatted.ends_with('\n') {
+        formatted.pop();
+    }
+    Ok(formatted)
+}
+
+fn format_code(rustfmt_path: &str, code: &str) -> Result<String, Error> {
+    let mut wrapped = WRAPPER_START.to_owned();
+    // We indent all non-empty lines, even those within multi-line string literals, so that
+    // removing the indentation afterwards restores those lines as they were.
+    for line in code.lines() {
+        if !line.is_empty() {
+            wrapped.push_str(INDENT);
+        }
+        wrapped.push_str(line);
+        wrapped.push('\n');
+    }
+    wrapped.push_str(WRAPPER_END);
+    let mut child = Command::new(rustfmt_path)
+        .arg("--edition")
+        .arg("2021")
+        // Allow for the indentation that we'll be removing.
+        .arg("--config")
+        .arg(format!("max_width={}", 100 + INDENT.len()))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|error| Error::Message(format!("Failed to run {rustfmt_path}: {error}")))?;
+    // rustfmt reads all its input before writing anything, so we can write all of our input
+    // before reading its output without risk of deadlock.
+    child.stdin.take().unwrap().write_all(wrapped.as_bytes())?;
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "rustfmt failed to format the code:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let formatted = String::from_utf8_lossy(&output.stdout);
+    let Some(body) = formatted
+        .strip_prefix(WRAPPER_START)
+        .and_then(|rest| rest.strip_suffix(WRAPPER_END))
+    else {
+        bail!("Unexpected output from rustfmt:\n{formatted}");
+    };
+    Ok(unindent(body))
+}
+
+fn unindent(code: &str) -> String {
+    let mut out = String::new();
+    for line in code.lines() {
+        out.push_str(line.strip_prefix(INDENT).unwrap_or(line));
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::unindent;
+
+    #[test]
+    fn test_unindent() {
+        assert_eq!(
+            unindent("    let a = \"\n  b\";\n\n    a.len()\n"),
+            "let a = \"\n  b\";\n\na.len()\n"
+        );
+    }
+}
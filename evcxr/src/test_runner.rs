@@ -0,0 +1,204 @@
+// Copyright 2023 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Parsing and presentation of the results of running `#[test]` functions with the standard test
+//! harness.
+
+use crate::errors::html_escape;
+use once_cell::sync::OnceCell;
+use regex::Regex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum TestOutcome {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+impl TestOutcome {
+    fn name(self) -> &'static str {
+        match self {
+            TestOutcome::Passed => "ok",
+            TestOutcome::Failed => "FAILED",
+            TestOutcome::Ignored => "ignored",
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            TestOutcome::Passed => "#008000",
+            TestOutcome::Failed => "#d00000",
+            TestOutcome::Ignored => "#b08800",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TestResult {
+    pub(crate) name: String,
+    pub(crate) outcome: TestOutcome,
+    /// What the test printed. The harness captures this, then prints it after all tests have run.
+    pub(crate) output: String,
+}
+
+/// Parses what the test harness prints when run with `--show-output`.
+pub(crate) fn parse_test_output(output: &str) -> Vec<TestResult> {
+    static TEST_RE: OnceCell<Regex> = OnceCell::new();
+    static OUTPUT_HEADER_RE: OnceCell<Regex> = OnceCell::new();
+    let test_re =
+        TEST_RE.get_or_init(|| Regex::new(r"^test (.+) \.\.\. (ok|FAILED|ignored)").unwrap());
+    let output_header_re =
+        OUTPUT_HEADER_RE.get_or_init(|| Regex::new("^---- (.+) std(out|err) ----$").unwrap());
+    let mut results: Vec<TestResult> = Vec::new();
+    // The test whose output we're currently reading, if any.
+    let mut current: Option<usize> = None;
+    for line in output.lines() {
+        if let Some(captures) = output_header_re.captures(line) {
+            current = results.iter().position(|result| result.name == captures[1]);
+        } else if line == "successes:" || line == "failures:" || line.starts_with("test result:") {
+            current = None;
+        } else if let Some(index) = current {
+            let output = &mut results[index].output;
+            output.push_str(line);
+            output.push('\n');
+        } else if let Some(captures) = test_re.captures(line) {
+            let outcome = match &captures[2] {
+                "ok" => TestOutcome::Passed,
+                "FAILED" => TestOutcome::Failed,
+                _ => TestOutcome::Ignored,
+            };
+            results.push(TestResult {
+                name: captures[1].to_owned(),
+                outcome,
+                output: String::new(),
+            });
+        }
+    }
+    for result in &mut results {
+        // Each test's output is followed by a blank line and a panic message is preceded by one.
+        result.output = result.output.trim_end().trim_start_matches('\n').to_owned();
+    }
+    results
+}
+
+fn summary(results: &[TestResult]) -> String {
+    let count = |outcome| {
+        results
+            .iter()
+            .filter(|result| result.outcome == outcome)
+            .count()
+    };
+    format!(
+        "{} passed; {} failed; {} ignored",
+        count(TestOutcome::Passed),
+        count(TestOutcome::Failed),
+        count(TestOutcome::Ignored)
+    )
+}
+
+pub(crate) fn to_text(results: &[TestResult]) -> String {
+    let mut text = String::new();
+    for result in results {
+        text.push_str(&format!(
+            "test {} ... {}\n",
+            result.name,
+            result.outcome.name()
+        ));
+        for line in result.output.lines() {
+            if !line.is_empty() {
+                text.push_str("    ");
+            }
+            text.push_str(line);
+            text.push('\n');
+        }
+    }
+    text.push_str(&summary(results));
+    text
+}
+
+pub(crate) fn to_html(results: &[TestResult]) -> String {
+    let mut html = String::new();
+    html.push_str("<table><tr><th>Test</th><th>Result</th><th>Output</th></tr>");
+    for result in results {
+        html.push_str("<tr><td>");
+        html_escape(&result.name, &mut html);
+        html.push_str(&format!(
+            "</td><td style=\"color: {}\">{}</td><td><pre>",
+            result.outcome.color(),
+            result.outcome.name()
+        ));
+        html_escape(&result.output, &mut html);
+        html.push_str("</pre></td></tr>");
+    }
+    html.push_str("</table><p>");
+    html.push_str(&summary(results));
+    html.push_str("</p>");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_test_output() {
+        let output = r#"
+running 3 tests
+test c ... ignored, slow
+test tests::a ... ok
+test b ... FAILED
+
+successes:
+
+---- tests::a stdout ----
+hello
+
+successes:
+    tests::a
+
+failures:
+
+---- b stdout ----
+
+thread 'b' panicked at 'oops', src/lib.rs:5:5
+
+
+failures:
+    b
+
+test result: FAILED. 1 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out
+"#;
+        let results = parse_test_output(output);
+        assert_eq!(
+            results,
+            vec![
+                TestResult {
+                    name: "c".to_owned(),
+                    outcome: TestOutcome::Ignored,
+                    output: String::new(),
+                },
+                TestResult {
+                    name: "tests::a".to_owned(),
+                    outcome: TestOutcome::Passed,
+                    output: "hello".to_owned(),
+                },
+                TestResult {
+                    name: "b".to_owned(),
+                    outcome: TestOutcome::Failed,
+                    output: "thread 'b' panicked at 'oops', src/lib.rs:5:5".to_owned(),
+                },
+            ]
+        );
+        assert_eq!(
+            to_text(&results),
+            "test c ... ignored\n\
+             test tests::a ... ok\n    hello\n\
+             test b ... FAILED\n    thread 'b' panicked at 'oops', src/lib.rs:5:5\n\
+             1 passed; 1 failed; 1 ignored"
+        );
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright 2023 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Golden-transcript tests. A transcript looks like a REPL session. Each input starts with a line
+//! prefixed by `>> `, optionally continued on subsequent lines prefixed by `.. `. The lines that
+//! follow, up until the next input, are the expected output. e.g.
+//!
+//! ```text
+//! >> let a = 40;
+//! >> println!("a={a}");
+//! a=40
+//! >> fn add(x: i32) -> i32 {
+//! ..     x + 2
+//! .. }
+//! >> add(a)
+//! 42
+//! >> let b: String = a;
+//! error[E0308]: mismatched types
+//! ```
+//!
+//! The output of an input consists of anything written to stdout, followed by the text/plain
+//! output if any, followed by one line per error. Panics are shown as `[panicked]`. Stderr isn't
+//! included. Before comparing, durations (e.g. "12ms") are replaced by `<TIME>` and the path of
+//! the context's temporary directory by `<TMPDIR>`, in both the expected and actual outputs.
+
+use crate::errors::Error;
+use crate::CommandContext;
+use once_cell::sync::OnceCell;
+use regex::Regex;
+use std::fmt;
+use std::path::Path;
+
+/// An input from a transcript, whose output differed from what the transcript expected.
+#[derive(Debug)]
+pub struct TranscriptMismatch {
+    /// The 1-based line number of the start of the input.
+    pub line: usize,
+    pub input: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Returned by `run_transcript` when the outputs of one or more inputs didn't match.
+pub struct TranscriptFailure {
+    pub mismatches: Vec<TranscriptMismatch>,
+}
+
+impl fmt::Display for TranscriptFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for mismatch in &self.mismatches {
+            writeln!(f, "Output mismatch for input at line {}:", mismatch.line)?;
+            for (index, line) in mismatch.input.lines().enumerate() {
+                let prefix = if index == 0 { ">>" } else { ".." };
+                writeln!(f, "{prefix} {line}")?;
+            }
+            for line in mismatch.expected.lines() {
+                writeln!(f, "-{line}")?;
+            }
+            for line in mismatch.actual.lines() {
+                writeln!(f, "+{line}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Implemented using Display so that the mismatches are readable when a test unwraps the result.
+impl fmt::Debug for TranscriptFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for TranscriptFailure {}
+
+/// Replays `transcript` through `context`, comparing the output of each input with what the
+/// transcript expects. `stdout` should be the stdout receiver that was returned when `context` was
+/// created. All inputs are run, even after a mismatch.
+pub fn run_transcript(
+    context: &mut CommandContext,
+    stdout: &crossbeam_channel::Receiver<String>,
+    transcript: &str,
+) -> Result<(), TranscriptFailure> {
+    // Discard anything written before we started.
+    stdout.try_iter().for_each(drop);
+    let tmpdir = context.tmpdir().to_owned();
+    let mut mismatches = Vec::new();
+    for entry in parse_transcript(transcript) {
+        let result = context.execute(&entry.input);
+        // Output from user code is all sent before execution completes.
+        let mut actual: String = stdout.try_iter().map(|line| line + "\n").collect();
+        format_result(result, &mut actual);
+        let expected = normalise(&entry.expected, &tmpdir);
+        let actual = normalise(&actual, &tmpdir);
+        if expected != actual {
+            mismatches.push(TranscriptMismatch {
+                line: entry.line,
+                input: entry.input,
+                expected,
+                actual,
+            });
+        }
+    }
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(TranscriptFailure { mismatches })
+    }
+}
+
+struct TranscriptEntry {
+    line: usize,
+    input: String,
+    expected: String,
+}
+
+fn parse_transcript(transcript: &str) -> Vec<TranscriptEntry> {
+    let mut entries: Vec<TranscriptEntry> = Vec::new();
+    for (index, line) in transcript.lines().enumerate() {
+        if let Some(input) = strip_marker(line, ">>") {
+            entries.push(TranscriptEntry {
+                line: index + 1,
+                input: format!("{input}\n"),
+                expected: String::new(),
+            });
+        } else if let Some(entry) = entries.last_mut() {
+            match strip_marker(line, "..") {
+                Some(continuation) if entry.expected.is_empty() => {
+                    entry.input.push_str(continuation);
+                    entry.input.push('\n');
+                }
+                _ => {
+                    entry.expected.push_str(line);
+                    entry.expected.push('\n');
+                }
+            }
+        }
+        // Anything before the first input is ignored.
+    }
+    entries
+}
+
+fn strip_marker<'a>(line: &'a str, marker: &str) -> Option<&'a str> {
+    if line == marker {
+        Some("")
+    } else {
+        line.strip_prefix(marker)?.strip_prefix(' ')
+    }
+}
+
+fn format_result(result: Result<crate::EvalOutputs, Error>, out: &mut String) {
+    match result {
+        Ok(outputs) => {
+            if let Some(text) = outputs.get("text/plain") {
+                out.push_str(text);
+                out.push('\n');
+            }
+            if outputs.panicked {
+                out.push_str("[panicked]\n");
+            }
+        }
+        Err(Error::CompilationErrors(errors)) => {
+            for error in errors {
+                match error.code() {
+                    Some(code) => out.push_str(&format!("error[{code}]: {}\n", error.message())),
+                    None => out.push_str(&format!("error: {}\n", error.message())),
+                }
+            }
+        }
+        Err(error) => out.push_str(&format!("error: {error}\n")),
+    }
+}
+
+/// Makes output comparable by replacing things that vary from run to run and ignoring trailing
+/// whitespace.
+fn normalise(output: &str, tmpdir: &Path) -> String {
+    static DURATION: OnceCell<Regex> = OnceCell::new();
+    let duration = DURATION.get_or_init(|| {
+        Regex::new("(?-u:\\b)[0-9]+(\\.[0-9]+)?(ns|µs|us|ms|s)(?-u:\\b)").unwrap()
+    });
+    let tmpdir = tmpdir.to_string_lossy();
+    let mut normalised = String::new();
+    for line in output.lines() {
+        let line = line.replace(tmpdir.as_ref(), "<TMPDIR>");
+        normalised.push_str(duration.replace_all(&line, "<TIME>").trim_end());
+        normalised.push('\n');
+    }
+    normalised.trim_end_matches('\n').to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let entries = parse_transcript(
+            "Header\n>> let a = 1;\n>> fn f() {\n..   1\n.. }\n>> a\n1\n.. not input\n>>\n",
+        );
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|e| (e.line, e.input.as_str(), e.expected.as_str()))
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (2, "let a = 1;\n", ""),
+                (3, "fn f() {\n  1\n}\n", ""),
+                (6, "a\n", "1\n.. not input\n"),
+                (9, "\n", ""),
+            ]
+        );
+    }
+
+    #[test]
+    fn normalisation() {
+        let tmpdir = Path::new("/tmp/.tmpAbc123");
+        assert_eq!(
+            normalise(
+                "Took 12ms  \nCompiled /tmp/.tmpAbc123/src/lib.rs in 1.5s\n10 items\n\n",
+                tmpdir
+            ),
+            "Took <TIME>\nCompiled <TMPDIR>/src/lib.rs in <TIME>\n10 items"
+        );
+    }
+}
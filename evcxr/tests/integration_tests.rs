@@ -15,6 +15,7 @@ use std::collections::HashSet;
 use std::io;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::path::Path;
 use std::sync::Mutex;
 
 #[track_caller]
@@ -282,7 +283,11 @@ fn function_panics_with_variable_preserving() {
         let b = 42;
     "#,
     );
-    eval!(e, panic!("Intentional panic {}", b););
+    assert!(
+        e.execute(stringify!(panic!("Intentional panic {}", b);))
+            .unwrap()
+            .panicked
+    );
     // The variable a isn't referenced by the code that panics, while the variable b implements
     // Copy, so neither should be lost.
     assert_eq!(
@@ -426,6 +431,302 @@ fn crate_name_with_hyphens() {
     assert_eq!(outputs.content_by_mime_type, text_plain("42"));
 }
 
+#[test]
+#[cfg(target_os = "linux")]
+fn link_native_library() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let lib_dir = tempfile::tempdir().unwrap();
+    let c_file = lib_dir.path().join("add.c");
+    std::fs::write(
+        &c_file,
+        "int evcxr_test_add(int a, int b) { return a + b; }\n",
+    )
+    .unwrap();
+    let status = std::process::Command::new("cc")
+        .args(["-shared", "-fPIC", "-o"])
+        .arg(lib_dir.path().join("libevcxr_test_add.so"))
+        .arg(&c_file)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    // A library that can't be found should fail and not be retained.
+    assert!(e.execute(":link no_such_library\n40").is_err());
+    let to_run = format!(
+        ":link evcxr_test_add {}\n\
+         extern \"C\" {{ fn evcxr_test_add(a: i32, b: i32) -> i32; }}\n\
+         unsafe {{ evcxr_test_add(40, 2) }}",
+        lib_dir.path().display()
+    );
+    let outputs = e.execute(&to_run).unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("42"));
+    // The library remains linked for subsequent evaluations.
+    assert_eq!(eval!(e, unsafe { evcxr_test_add(1, 2) }), text_plain("3"));
+    // Tests are linked against it too.
+    e.execute("#[test]\nfn links() { assert_eq!(unsafe { evcxr_test_add(1, 2) }, 3); }")
+        .unwrap();
+    let outputs = e.execute(":test").unwrap();
+    let text = outputs.get("text/plain").unwrap();
+    assert!(text.ends_with("1 passed; 0 failed; 0 ignored"), "{text}");
+}
+
+#[test]
+fn c_and_cpp_cells() {
+    let (mut e, _) = new_command_context_and_outputs();
+    e.execute(":c\nint evcxr_test_c(int a, int b) { return a * b; }")
+        .unwrap();
+    e.execute(
+        ":cpp strings\n\
+         #include <string>\n\
+         extern \"C\" int evcxr_test_cpp() { return std::string(\"abc\").size(); }",
+    )
+    .unwrap();
+    e.execute(
+        "extern \"C\" { fn evcxr_test_c(a: i32, b: i32) -> i32; fn evcxr_test_cpp() -> i32; }",
+    )
+    .unwrap();
+    assert_eq!(
+        eval!(e, unsafe { evcxr_test_c(6, 7) + evcxr_test_cpp() }),
+        text_plain("45")
+    );
+    // Running a cell with the same name replaces the previous source.
+    e.execute(":c\nint evcxr_test_c(int a, int b) { return a + b; }")
+        .unwrap();
+    assert_eq!(eval!(e, unsafe { evcxr_test_c(6, 7) }), text_plain("13"));
+    // Invalid code is reported and the previous source is kept.
+    assert!(e
+        .execute(":c\nint evcxr_test_c(int a, int b) { return a +; }")
+        .is_err());
+    assert_eq!(eval!(e, unsafe { evcxr_test_c(1, 2) }), text_plain("3"));
+}
+
+#[test]
+fn build_script() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let dir = tempfile::tempdir().unwrap();
+    let build_rs = dir.path().join("build.rs");
+    std::fs::write(
+        &build_rs,
+        "fn main() { println!(\"cargo:rustc-env=EVCXR_BUILD_RS_VALUE=42\"); }",
+    )
+    .unwrap();
+    assert!(e.execute(":build_rs no_such_build.rs").is_err());
+    let outputs = e
+        .execute(&format!(":build_rs {}", build_rs.display()))
+        .unwrap();
+    assert!(outputs.content_by_mime_type["text/plain"].contains("build.rs"));
+    assert_eq!(eval!(e, env!("EVCXR_BUILD_RS_VALUE")), text_plain("\"42\""));
+}
+
+#[test]
+fn asm_and_llvm_ir() {
+    let (mut e, _) = new_command_context_and_outputs();
+    e.execute("fn add_one(x: u64) -> u64 { x + 1 }").unwrap();
+    let asm = e.execute(":asm add_one").unwrap();
+    assert!(asm.content_by_mime_type["text/plain"].contains("add_one"));
+    assert!(asm.content_by_mime_type["text/html"].starts_with("<pre>"));
+    // Evcxr's own functions are filtered out.
+    assert!(!asm.content_by_mime_type["text/plain"].contains("run_user_code"));
+    let ir = e.execute(":llvm_ir add_one").unwrap();
+    assert!(ir.content_by_mime_type["text/plain"].contains("define"));
+    assert!(e.execute(":asm no_such_function").is_err());
+}
+
+#[test]
+fn expand_and_mir_require_nightly() {
+    let (mut e, _) = new_command_context_and_outputs();
+    assert!(e.execute(":expand").is_err());
+    e.execute("fn add_one(x: u64) -> u64 { x + 1 }").unwrap();
+    for command in [":expand", ":mir add_one"] {
+        let error = e.execute(command).unwrap_err().to_string();
+        assert!(error.contains("nightly"), "{error}");
+    }
+}
+
+#[test]
+fn unknown_toolchain() {
+    // Without rustup, we can't tell whether a toolchain exists.
+    if std::process::Command::new("rustup").output().is_err() {
+        return;
+    }
+    let (mut e, _) = new_command_context_and_outputs();
+    let error = e
+        .execute(":toolchain evcxr-no-such-toolchain")
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("evcxr-no-such-toolchain"), "{error}");
+    // The toolchain wasn't changed, so evaluation still works.
+    assert_eq!(
+        e.execute(":toolchain").unwrap().content_by_mime_type,
+        text_plain("Toolchain: \n")
+    );
+    assert_eq!(
+        e.execute("40 + 2").unwrap().content_by_mime_type,
+        text_plain("42")
+    );
+}
+
+#[test]
+fn clippy() {
+    let (mut e, _) = new_command_context_and_outputs();
+    assert!(e.execute(":clippy").is_err());
+    let code = "let x: u32 = 1;\nlet y = x.clone();";
+    let lints = e.clippy(code).unwrap();
+    assert_eq!(lints.len(), 1);
+    assert_eq!(lints[0].code(), Some("clippy::clone_on_copy"));
+    assert_eq!(lints[0].level(), "warning");
+    let span = lints[0].primary_spanned_message().unwrap().span.unwrap();
+    assert_eq!(span.start_line, 2);
+    e.execute(code).unwrap();
+    let outputs = e.execute(":clippy").unwrap();
+    assert!(outputs.content_by_mime_type["text/html"].contains("clone_on_copy"));
+}
+
+#[test]
+fn warnings() {
+    let (mut e, _) = new_command_context_and_outputs();
+    e.execute("fn f() -> Result<(), ()> { Ok(()) }").unwrap();
+    let outputs = e.execute("f();").unwrap();
+    assert_eq!(outputs.warnings.len(), 1);
+    assert_eq!(outputs.warnings[0].code(), Some("unused_must_use"));
+    assert_eq!(outputs.warnings[0].level(), "warning");
+    // Code without warnings doesn't get warnings from earlier evaluations.
+    assert!(e.execute("let a = 1;").unwrap().warnings.is_empty());
+    e.execute(":warnings off").unwrap();
+    assert!(e.execute("f();").unwrap().warnings.is_empty());
+    e.execute(":warnings deny").unwrap();
+    assert!(matches!(
+        e.execute("f();"),
+        Err(Error::CompilationErrors(errors)) if errors.len() == 1
+    ));
+    assert!(e.execute(":warnings sometimes").is_err());
+}
+
+#[test]
+fn fix() {
+    let (mut e, _) = new_command_context_and_outputs();
+    assert!(e.execute(":fix").is_err());
+    e.execute("fn f(x: &i32) -> i32 { *x }").unwrap();
+    assert!(e.execute("let a = f(1);").is_err());
+    let outputs = e.execute(":fix").unwrap();
+    assert_eq!(outputs.next_input.as_deref(), Some("let a = f(&1);"));
+    assert_eq!(outputs.content_by_mime_type, text_plain("let a = f(&1);\n"));
+    // Errors without suggestions we can apply aren't fixed.
+    assert!(e.execute("let b: i32 = undefined_variable;").is_err());
+    assert!(e.execute(":fix").is_err());
+}
+
+#[test]
+fn rustfmt() {
+    let (e, _) = new_command_context_and_outputs();
+    assert_eq!(
+        e.rustfmt(":dep foo = \"1.0\"\n:opt   2\nlet  x=vec![1,2];\nfn  f(){}\nx.len( )")
+            .unwrap(),
+        ":dep foo = \"1.0\"\n:opt   2\nlet x = vec![1, 2];\nfn f() {}\nx.len()"
+    );
+    assert_eq!(
+        e.rustfmt("let s = \"a\n    b\";\nif true {\n1} else {2}\n")
+            .unwrap(),
+        "let s = \"a\n    b\";\nif true {\n    1\n} else {\n    2\n}\n"
+    );
+    assert!(e.rustfmt("let x = ;").is_err());
+}
+
+#[test]
+fn explain() {
+    let (mut e, _) = new_command_context_and_outputs();
+    assert!(e.execute(":explain").is_err());
+    let outputs = e.execute(":explain E0382").unwrap();
+    let markdown = outputs.get("text/markdown").unwrap();
+    assert!(markdown.contains("moved"));
+    assert!(markdown.contains("```rust\n"));
+    assert!(!outputs.get("text/plain").unwrap().contains("```"));
+    assert!(e.execute(":explain e0382").is_ok());
+    assert!(e.execute(":explain E9999").is_err());
+    assert!(e.execute("let a: i32 = \"\";").is_err());
+    let outputs = e.execute(":explain").unwrap();
+    assert!(outputs
+        .get("text/markdown")
+        .unwrap()
+        .contains("Expected type did not match"));
+}
+
+#[test]
+fn test_command() {
+    let (mut e, _) = new_command_context_and_outputs();
+    assert_eq!(
+        e.execute(":test").unwrap().content_by_mime_type,
+        text_plain("No tests found\n")
+    );
+    e.execute(
+        r#"
+        fn add(a: i32, b: i32) -> i32 { a + b }
+        #[test]
+        fn adds() { println!("checking"); assert_eq!(add(1, 2), 3); }
+        #[test]
+        fn broken() { assert_eq!(add(1, 2), 4); }
+        "#,
+    )
+    .unwrap();
+    let outputs = e.execute(":test").unwrap();
+    let text = outputs.get("text/plain").unwrap();
+    assert!(text.starts_with("test adds ... ok\n    checking\ntest broken ... FAILED\n"));
+    assert!(text.ends_with("1 passed; 1 failed; 0 ignored"));
+    assert!(outputs.get("text/html").unwrap().starts_with("<table>"));
+    let text = e
+        .execute(":test add")
+        .unwrap()
+        .get("text/plain")
+        .unwrap()
+        .to_owned();
+    assert!(text.starts_with("test adds ... ok\n"));
+    assert!(text.ends_with("1 passed; 0 failed; 0 ignored"));
+    // Running tests doesn't interfere with normal evaluation.
+    assert_eq!(
+        e.execute("add(1, 2)").unwrap().content_by_mime_type,
+        text_plain("3")
+    );
+}
+
+#[test]
+fn bench() {
+    let (mut e, _) = new_command_context_and_outputs();
+    e.execute("let v: Vec<u64> = (0..100).collect();").unwrap();
+    let outputs = e.execute(":bench v.iter().sum::<u64>()").unwrap();
+    let text = outputs.get("text/plain").unwrap();
+    for row in ["mean:", "median:", "std dev:", "throughput:"] {
+        assert!(text.contains(row), "{text}");
+    }
+    assert!(text.ends_with("at opt-level 2"), "{text}");
+    assert!(outputs.get("text/html").unwrap().starts_with("<table>"));
+    // Benchmarking doesn't affect variables.
+    assert_eq!(
+        e.execute("v.len()").unwrap().content_by_mime_type,
+        text_plain("100")
+    );
+    // Names used by the benchmarking code don't shadow variables used by the expression.
+    e.execute("let start = 5u64;").unwrap();
+    assert!(e.execute(":bench start * 2").is_ok());
+    assert!(e.execute(":bench").is_err());
+    assert!(e.execute(":bench undefined_variable").is_err());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn memory() {
+    let (mut e, _) = new_command_context_and_outputs();
+    assert!(e.execute("let a = 1;").unwrap().memory.is_none());
+    e.execute(":memory").unwrap();
+    let outputs = e
+        .execute("let v = vec![1u8; 50_000_000]; v.iter().filter(|x| **x == 1).count()")
+        .unwrap();
+    let memory = outputs.memory.unwrap();
+    assert!(memory.rss_delta() > 40_000_000, "{memory:?}");
+    assert!(memory.peak_rss_delta() > 40_000_000, "{memory:?}");
+    let memory = e.execute("drop(v);").unwrap().memory.unwrap();
+    assert!(memory.rss_delta() < -40_000_000, "{memory:?}");
+    assert_eq!(memory.peak_rss_delta(), 0);
+}
+
 // A collection of bits of code that are invalid. Our bar here is that we don't
 // crash and each thing we try to evaluate results in an error. The actual
 // errors will be produced by the rust compiler and we don't want to tie our
@@ -497,6 +798,27 @@ fn continue_execution_after_bad_use_statement() {
     assert_eq!(eval!(e, "f".to_string() + "oo"), text_plain("\"foo\""));
 }
 
+#[test]
+fn html_error_report() {
+    let mut e = new_context();
+    let source = "let v: Vec<i32> = Vec::new();\nlet s: String = v;";
+    match e.execute(source) {
+        Err(Error::CompilationErrors(errors)) => {
+            assert_eq!(errors.len(), 1);
+            let html = errors[0].build_html_report(source);
+            assert!(html.contains("https://doc.rust-lang.org/error_codes/E0308.html"));
+            assert!(html.contains("mismatched types"));
+            assert!(html.contains("<code>:explain</code>"));
+            // Source is escaped and only lines with spans are included.
+            assert!(html.contains("let s: <span"));
+            assert!(html.contains("underline;\">String</span>"));
+            assert!(!html.contains("Vec<i32>"));
+            assert!(html.contains("expected `String`, found `Vec&lt;i32&gt;`"));
+        }
+        x => panic!("Unexpected result: {:?}", x),
+    }
+}
+
 #[test]
 fn error_from_macro_expansion() {
     let mut e = new_context();
@@ -1058,3 +1380,208 @@ let s2 = "さび  äää"; let s2: String = 42; fn foo() -> i32 {
     // Dropped variables shouldn't report errors.
     assert_no_errors(&mut ctx, "let s1 = String::new(); std::mem::drop(s1);");
 }
+
+/// A minimal executor, so that we can test futures without depending on an async runtime.
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    struct ThreadWaker(std::thread::Thread);
+    impl std::task::Wake for ThreadWaker {
+        fn wake(self: std::sync::Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+    let waker = std::task::Waker::from(std::sync::Arc::new(ThreadWaker(std::thread::current())));
+    let mut cx = std::task::Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    loop {
+        if let std::task::Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::park();
+    }
+}
+
+#[test]
+fn async_execute_and_cancel() {
+    let (ctx, outputs) = new_command_context_and_outputs();
+    send_output(outputs.stderr, io::stderr());
+    let ctx = evcxr::AsyncCommandContext::new(ctx);
+    let (evaluation, _) = ctx.execute("40 + 2");
+    assert_eq!(block_on(evaluation).unwrap().get("text/plain"), Some("42"));
+
+    // Cancel while compiling or running, whichever we happen to be doing at the time.
+    let (evaluation, cancel_handle) =
+        ctx.execute("std::thread::sleep(std::time::Duration::from_secs(600));");
+    // Cancel an evaluation that hasn't started yet.
+    let (queued_evaluation, queued_cancel_handle) = ctx.execute("40 + 2");
+    queued_cancel_handle.cancel();
+    std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(500));
+        cancel_handle.cancel();
+    });
+    assert!(matches!(block_on(evaluation), Err(Error::Cancelled)));
+    assert!(matches!(block_on(queued_evaluation), Err(Error::Cancelled)));
+
+    // Make sure the context is still usable.
+    let (evaluation, _) = ctx.execute("40 + 2");
+    assert_eq!(block_on(evaluation).unwrap().get("text/plain"), Some("42"));
+}
+
+#[test]
+fn transcript() {
+    let (mut ctx, outputs) = new_command_context_and_outputs();
+    evcxr::run_transcript(
+        &mut ctx,
+        &outputs.stdout,
+        r#"
+>> let a = 40;
+>> println!("a={a}");
+a=40
+>> fn add(x: i32) -> i32 {
+..     x + 2
+.. }
+>> add(a)
+42
+>> let b: String = a;
+error[E0308]: mismatched types
+"#,
+    )
+    .unwrap();
+
+    let failure =
+        evcxr::run_transcript(&mut ctx, &outputs.stdout, ">> a\n41\n>> a + 1\n41\n").unwrap_err();
+    assert_eq!(failure.mismatches.len(), 1);
+    assert_eq!(failure.mismatches[0].line, 1);
+    assert_eq!(failure.mismatches[0].actual, "40");
+    assert_eq!(
+        failure.to_string(),
+        "Output mismatch for input at line 1:\n>> a\n-41\n+40\n"
+    );
+}
+
+#[test]
+fn comms() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    // Until user code registers something to handle comms, they're closed when opened.
+    let output = e
+        .handle_comm_message(r#"{"type":"open","comm_id":"c1","target_name":"echo","data":{}}"#)
+        .unwrap();
+    assert!(output.is_empty());
+    assert_eq!(
+        outputs.comm.try_recv().unwrap(),
+        r#"{"type":"close","comm_id":"c1"}"#
+    );
+
+    let runtime_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../evcxr_runtime");
+    eval_and_unwrap(
+        &mut e,
+        &format!(
+            ":dep evcxr_runtime = {{ path = \"{}\" }}",
+            runtime_dir.to_string_lossy().replace('\\', "/")
+        ),
+    );
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        evcxr_runtime::register_comm_target("echo", |comm, data| {
+            let reply_comm = comm.clone();
+            comm.on_msg(move |data| {
+                print!("partial line");
+                reply_comm.send(data);
+            });
+            comm.send(data);
+        });
+        "#,
+    );
+    e.handle_comm_message(r#"{"type":"open","comm_id":"c2","target_name":"echo","data":{"a":1}}"#)
+        .unwrap();
+    // Handlers registered by earlier cells are still called after later cells have run.
+    eval_and_unwrap(&mut e, "let x = 1;");
+    e.handle_comm_message(r#"{"type":"msg","comm_id":"c2","data":[2]}"#)
+        .unwrap();
+    let messages: Vec<String> = outputs.comm.try_iter().collect();
+    assert_eq!(
+        messages,
+        vec![
+            r#"{"type":"msg","comm_id":"c2","data":{"a":1}}"#,
+            r#"{"type":"msg","comm_id":"c2","data":[2]}"#,
+        ]
+    );
+    assert!(outputs.stdout.try_iter().any(|line| line == "partial line"));
+}
+
+#[test]
+fn variable_previews() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        struct NoDebug;
+        struct Panics(String);
+        impl std::fmt::Debug for Panics {
+            fn fmt(&self, _: &mut std::fmt::Formatter) -> std::fmt::Result { panic!("no") }
+        }
+        let mut a = 42;
+        let b = NoDebug;
+        let c = "x".repeat(20);
+        let d = Panics("kept".to_owned());
+        "#,
+    );
+    let variables: Vec<_> = e
+        .variables()
+        .into_iter()
+        .map(|v| (v.name, v.type_name, v.is_mut))
+        .collect();
+    assert_eq!(
+        variables,
+        vec![
+            ("a".to_owned(), "i32".to_owned(), true),
+            ("b".to_owned(), "NoDebug".to_owned(), false),
+            ("c".to_owned(), "String".to_owned(), false),
+            ("d".to_owned(), "Panics".to_owned(), false),
+        ]
+    );
+    let previews = e.variable_previews(10).unwrap();
+    assert_eq!(previews.get("a").map(String::as_str), Some("42"));
+    // No Debug implementation.
+    assert_eq!(previews.get("b"), None);
+    assert_eq!(previews.get("c").map(String::as_str), Some("\"xxxxxxxxx…"));
+    // Debug implementation panics.
+    assert_eq!(previews.get("d"), None);
+    assert_eq!(previews.len(), 2);
+    // Obtaining previews, even ones that panic, shouldn't affect variables.
+    assert_eq!(eval!(e, a + 1), text_plain("43"));
+    assert_eq!(eval!(e, d.0.len()), text_plain("4"));
+}
+
+#[test]
+fn eval_readonly() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, "let mut v = vec![1, 2];");
+    assert_eq!(
+        e.eval_readonly("v.len()").unwrap().content_by_mime_type,
+        text_plain("2")
+    );
+    // Variables can't be moved or modified.
+    assert!(e.eval_readonly("v.push(3)").is_err());
+    assert!(e.eval_readonly("{ let w: Vec<i32> = v; w }").is_err());
+    assert!(e.eval_readonly("panic!()").is_err());
+    // Nothing defined by the expression is kept.
+    e.eval_readonly("{ let w = 5; w }").unwrap();
+    assert!(e.execute("w").is_err());
+    assert_eq!(eval!(e, v), text_plain("[1, 2]"));
+}
+
+#[test]
+fn restart() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":opt 1\nlet a = 1;");
+    e.restart().unwrap();
+    assert!(e.variables_and_types().next().is_none());
+    // Config should be back to its defaults, so toggling gives the same result as in a new context.
+    let (mut fresh, _) = new_command_context_and_outputs();
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":opt"),
+        eval_and_unwrap(&mut fresh, ":opt")
+    );
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+}
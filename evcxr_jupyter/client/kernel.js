@@ -41,6 +41,9 @@ define([
         return new Promise(function (resolve, reject) {
             let cargoCheckComm = Jupyter.notebook.kernel.comm_manager.new_comm('evcxr-cargo-check', {
                 code: text,
+                // Lints from Clippy can be enabled by setting "evcxr": {"clippy": true} in the
+                // notebook's metadata.
+                clippy: Boolean(Jupyter.notebook.metadata.evcxr && Jupyter.notebook.metadata.evcxr.clippy),
             });
             cargoCheckComm.on_msg(function (msg) {
                 let found = [];
@@ -57,6 +60,24 @@ define([
         });
     }
 
+    function rustfmtSelectedCell() {
+        let cell = Jupyter.notebook.get_selected_cell();
+        if (cell.cell_type !== 'code') {
+            return;
+        }
+        let rustfmtComm = Jupyter.notebook.kernel.comm_manager.new_comm('evcxr-rustfmt', {
+            code: cell.get_text(),
+        });
+        rustfmtComm.on_msg(function (msg) {
+            let data = msg.content.data;
+            if (data.formatted !== undefined) {
+                cell.set_text(data.formatted);
+            } else {
+                console.error(data.error);
+            }
+        });
+    }
+
     return {
         onload: function () {
             $('head').append(
@@ -65,6 +86,12 @@ define([
             events.on('create.Cell', cellCreated);
             initExistingCells();
             CodeMirror.registerHelper("lint", "rust", lintText);
+            let rustfmtAction = Jupyter.actions.register({
+                icon: 'fa-align-left',
+                help: 'Format the selected cell with rustfmt',
+                handler: rustfmtSelectedCell,
+            }, 'rustfmt-cell', 'evcxr');
+            Jupyter.toolbar.add_buttons_group([rustfmtAction]);
         }
     }
 
@@ -6,26 +6,129 @@
 // or https://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use anyhow::bail;
 use anyhow::Result;
-use hmac::digest::KeyInit;
 use hmac::Hmac;
+use hmac::Mac;
+use sha1::Sha1;
 use sha2::Sha256;
+use sha2::Sha512;
 
-pub(crate) type HmacSha256 = Hmac<Sha256>;
+/// The algorithm used to sign messages. See the `signature_scheme` field of
+/// https://jupyter-client.readthedocs.io/en/latest/kernels.html#connection-files
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SignatureScheme {
+    HmacSha1,
+    HmacSha256,
+    HmacSha512,
+}
+
+impl SignatureScheme {
+    pub(crate) fn parse(scheme: &str) -> Result<SignatureScheme> {
+        Ok(match scheme {
+            "hmac-sha1" => SignatureScheme::HmacSha1,
+            "hmac-sha256" => SignatureScheme::HmacSha256,
+            "hmac-sha512" => SignatureScheme::HmacSha512,
+            _ => bail!("Unsupported signature scheme '{}'", scheme),
+        })
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum MessageMac {
+    HmacSha1(Hmac<Sha1>),
+    HmacSha256(Hmac<Sha256>),
+    HmacSha512(Hmac<Sha512>),
+}
+
+impl MessageMac {
+    fn new(scheme: SignatureScheme, key: &[u8]) -> MessageMac {
+        // HMAC accepts keys of any length, so new_from_slice can't fail.
+        match scheme {
+            SignatureScheme::HmacSha1 => {
+                MessageMac::HmacSha1(Hmac::new_from_slice(key).expect("Shouldn't fail with HMAC"))
+            }
+            SignatureScheme::HmacSha256 => {
+                MessageMac::HmacSha256(Hmac::new_from_slice(key).expect("Shouldn't fail with HMAC"))
+            }
+            SignatureScheme::HmacSha512 => {
+                MessageMac::HmacSha512(Hmac::new_from_slice(key).expect("Shouldn't fail with HMAC"))
+            }
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            MessageMac::HmacSha1(mac) => mac.update(data),
+            MessageMac::HmacSha256(mac) => mac.update(data),
+            MessageMac::HmacSha512(mac) => mac.update(data),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Vec<u8> {
+        match self {
+            MessageMac::HmacSha1(mac) => mac.finalize().into_bytes().to_vec(),
+            MessageMac::HmacSha256(mac) => mac.finalize().into_bytes().to_vec(),
+            MessageMac::HmacSha512(mac) => mac.finalize().into_bytes().to_vec(),
+        }
+    }
+
+    /// Checks `signature` in constant time.
+    pub(crate) fn verify(self, signature: &[u8]) -> Result<()> {
+        let result = match self {
+            MessageMac::HmacSha1(mac) => mac.verify_slice(signature),
+            MessageMac::HmacSha256(mac) => mac.verify_slice(signature),
+            MessageMac::HmacSha512(mac) => mac.verify_slice(signature),
+        };
+        if let Err(error) = result {
+            bail!("{}", error);
+        }
+        Ok(())
+    }
+}
 
 pub(crate) struct Connection<S> {
     pub(crate) socket: S,
     /// Will be None if our key was empty (digest authentication disabled).
-    pub(crate) mac: Option<HmacSha256>,
+    pub(crate) mac: Option<MessageMac>,
 }
 
 impl<S: zeromq::Socket> Connection<S> {
-    pub(crate) fn new(socket: S, key: &str) -> Result<Self> {
+    pub(crate) fn new(socket: S, signature_scheme: SignatureScheme, key: &str) -> Result<Self> {
         let mac = if key.is_empty() {
             None
         } else {
-            Some(HmacSha256::new_from_slice(key.as_bytes()).expect("Shouldn't fail with HMAC"))
+            Some(MessageMac::new(signature_scheme, key.as_bytes()))
         };
         Ok(Connection { socket, mac })
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_schemes() {
+        // Digests of "message" keyed with "key", as computed by Python's hmac module.
+        for (scheme, expected) in [
+            ("hmac-sha1", "2088df74d5f2146b48146caf4965377e9d0be3a4"),
+            (
+                "hmac-sha256",
+                "6e9ef29b75fffc5b7abae527d58fdadb2fe42e7219011976917343065f58ed4a",
+            ),
+            (
+                "hmac-sha512",
+                "e477384d7ca229dd1426e64b63ebf2d36ebd6d7e669a6735424e72ea6c01d3f8\
+                 b56eb39c36d8232f5427999b8d1a3f9cd1128fc69f4d75b434216810fa367e98",
+            ),
+        ] {
+            let scheme = SignatureScheme::parse(scheme).unwrap();
+            let mut mac = MessageMac::new(scheme, b"key");
+            mac.update(b"message");
+            assert_eq!(hex::encode(mac.clone().finalize()), expected);
+            mac.verify(&hex::decode(expected).unwrap()).unwrap();
+        }
+        assert!(SignatureScheme::parse("hmac-md5").is_err());
+    }
+}
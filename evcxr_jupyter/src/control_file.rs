@@ -10,6 +10,7 @@
 // https://github.com/rust-lang/rust/issues/45601 - but that's now long fixed
 // and we've dropped support for old version for rustc prior to the fix.
 
+use crate::connection::SignatureScheme;
 use anyhow::anyhow;
 use anyhow::Result;
 use std::fs;
@@ -24,6 +25,7 @@ pub(crate) struct Control {
     pub(crate) transport: String,
     pub(crate) ip: String,
     pub(crate) key: String,
+    pub(crate) signature_scheme: SignatureScheme,
 }
 
 macro_rules! parse_to_var {
@@ -46,6 +48,11 @@ impl Control {
         parse_to_var!(control_json, transport, as_str);
         parse_to_var!(control_json, ip, as_str);
         parse_to_var!(control_json, key, as_str);
+        // Older connection files may not specify a scheme, in which case Jupyter uses HMAC-SHA256.
+        let signature_scheme = match control_json["signature_scheme"].as_str() {
+            Some(scheme) => SignatureScheme::parse(scheme)?,
+            None => SignatureScheme::HmacSha256,
+        };
         Ok(Control {
             control_port,
             shell_port,
@@ -55,6 +62,7 @@ impl Control {
             transport: transport.to_owned(),
             key: key.to_owned(),
             ip: ip.to_owned(),
+            signature_scheme,
         })
     }
 }
@@ -17,18 +17,58 @@ use evcxr::CommandContext;
 use evcxr::Theme;
 use json::JsonValue;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 use tokio::sync::Mutex;
 
+const INITIAL_EXECUTION_COUNT: u32 = 1;
+
+/// Maximum length of the previews of variable values sent to evcxr-variables comms.
+const MAX_VARIABLE_PREVIEW_CHARS: usize = 100;
+
 // Note, to avoid potential deadlocks, each thread should lock at most one mutex at a time.
 #[derive(Clone)]
 pub(crate) struct Server {
     iopub: Arc<Mutex<Connection<zeromq::PubSocket>>>,
     stdin: Arc<Mutex<Connection<zeromq::RouterSocket>>>,
     latest_execution_request: Arc<Mutex<Option<JupyterMessage>>>,
+    execution_count: Arc<Mutex<u32>>,
+    /// Target names of the currently open comms that are handled by user code, keyed by comm ID.
+    user_comms: Arc<Mutex<HashMap<String, String>>>,
+    /// IDs of open comms with the evcxr-variables target.
+    variable_comms: Arc<Mutex<HashSet<String>>>,
+    /// Comm messages to be passed to user code, together with their type ("open", "msg" or
+    /// "close"). Handling these waits for any cell that's executing, so they're handled in order
+    /// by a separate task, rather than on the shell channel. This means that they're not ordered
+    /// with respect to execute requests. A message received while a cell is executing may be
+    /// handled before or after cells that were requested after it.
+    forwarded_comm_messages: tokio::sync::mpsc::UnboundedSender<(&'static str, JupyterMessage)>,
     shutdown_sender: Arc<Mutex<Option<crossbeam_channel::Sender<()>>>>,
     tokio_handle: tokio::runtime::Handle,
+    kernel_options: Arc<KernelOptions>,
+}
+
+/// Options from the kernelspec, which are applied on startup and whenever the kernel restarts.
+#[derive(Default)]
+pub(crate) struct KernelOptions {
+    pub(crate) toolchain: Option<String>,
+    pub(crate) opt_level: Option<String>,
+}
+
+impl KernelOptions {
+    /// Loads the user's config, then applies our options, which take precedence.
+    fn configure(&self, context: &mut CommandContext) -> Result<(), evcxr::Error> {
+        context.execute(":load_config")?;
+        if let Some(toolchain) = &self.toolchain {
+            context.execute(&format!(":toolchain {toolchain}"))?;
+        }
+        if let Some(opt_level) = &self.opt_level {
+            context.execute(&format!(":opt {opt_level}"))?;
+        }
+        Ok(())
+    }
 }
 
 struct ShutdownReceiver {
@@ -39,7 +79,7 @@ struct ShutdownReceiver {
 }
 
 impl Server {
-    pub(crate) fn run(config: &control_file::Control) -> Result<()> {
+    pub(crate) fn run(config: &control_file::Control, kernel_options: KernelOptions) -> Result<()> {
         let runtime = tokio::runtime::Builder::new_multi_thread()
             // We only technically need 1 thread. However we've observed that
             // when using vscode's jupyter extension, we can get requests on the
@@ -56,7 +96,7 @@ impl Server {
             .unwrap();
         let handle = runtime.handle().clone();
         runtime.block_on(async {
-            let shutdown_receiver = Self::start(config, handle).await?;
+            let shutdown_receiver = Self::start(config, handle, kernel_options).await?;
             shutdown_receiver.wait_for_shutdown().await;
             let result: Result<()> = Ok(());
             result
@@ -67,6 +107,7 @@ impl Server {
     async fn start(
         config: &control_file::Control,
         tokio_handle: tokio::runtime::Handle,
+        kernel_options: KernelOptions,
     ) -> Result<ShutdownReceiver> {
         let mut heartbeat = bind_socket::<zeromq::RepSocket>(config, config.hb_port).await?;
         let shell_socket = bind_socket::<zeromq::RouterSocket>(config, config.shell_port).await?;
@@ -77,13 +118,20 @@ impl Server {
         let iopub = Arc::new(Mutex::new(iopub_socket));
 
         let (shutdown_sender, shutdown_receiver) = crossbeam_channel::unbounded();
+        let (forwarded_comm_messages, mut forwarded_comm_message_receiver) =
+            tokio::sync::mpsc::unbounded_channel();
 
         let server = Server {
             iopub,
             latest_execution_request: Arc::new(Mutex::new(None)),
+            execution_count: Arc::new(Mutex::new(INITIAL_EXECUTION_COUNT)),
+            user_comms: Arc::new(Mutex::new(HashMap::new())),
+            variable_comms: Arc::new(Mutex::new(HashSet::new())),
+            forwarded_comm_messages,
             stdin: Arc::new(Mutex::new(stdin_socket)),
             shutdown_sender: Arc::new(Mutex::new(Some(shutdown_sender))),
             tokio_handle,
+            kernel_options: Arc::new(kernel_options),
         };
 
         let (execution_sender, mut execution_receiver) = tokio::sync::mpsc::unbounded_channel();
@@ -96,17 +144,37 @@ impl Server {
             }
         });
         let (mut context, outputs) = CommandContext::new()?;
-        context.execute(":load_config")?;
-        let process_handle = context.process_handle();
+        server.kernel_options.configure(&mut context)?;
+        let interrupt_handle = context.interrupt_handle();
         let context = Arc::new(std::sync::Mutex::new(context));
         {
             let server = server.clone();
+            let context = context.clone();
             tokio::spawn(async move {
-                if let Err(error) = server.handle_control(control_socket, process_handle).await {
+                if let Err(error) = server
+                    .handle_control(control_socket, interrupt_handle, context)
+                    .await
+                {
                     eprintln!("control error: {error:?}");
                 }
             });
         }
+        {
+            let context = context.clone();
+            let server = server.clone();
+            tokio::spawn(async move {
+                while let Some((message_type, message)) =
+                    forwarded_comm_message_receiver.recv().await
+                {
+                    if let Err(error) = server
+                        .forward_comm_message(message_type, message, &context)
+                        .await
+                    {
+                        eprintln!("comm error: {error:?}");
+                    }
+                }
+            });
+        }
         {
             let context = context.clone();
             let server = server.clone();
@@ -143,6 +211,7 @@ impl Server {
             .clone()
             .start_output_pass_through_thread(
                 vec![("stdout", outputs.stdout), ("stderr", outputs.stderr)],
+                outputs.comm,
                 shutdown_receiver.clone(),
             )
             .await;
@@ -173,7 +242,6 @@ impl Server {
         receiver: &mut tokio::sync::mpsc::UnboundedReceiver<JupyterMessage>,
         execution_reply_sender: &tokio::sync::mpsc::UnboundedSender<JupyterMessage>,
     ) -> Result<()> {
-        let mut execution_count = 1;
         loop {
             let message = match receiver.recv().await {
                 Some(x) => x,
@@ -187,21 +255,36 @@ impl Server {
             // If we want this clone to be cheaper, we probably only need the header, not the
             // whole message.
             *self.latest_execution_request.lock().await = Some(message.clone());
-            let src = message.code().to_owned();
-            execution_count += 1;
-            message
-                .new_message("execute_input")
-                .with_content(object! {
-                    "execution_count" => execution_count,
-                    "code" => src
-                })
-                .send(&mut *self.iopub.lock().await)
-                .await?;
+            let silent = message.get_content()["silent"].as_bool().unwrap_or(false);
+            // Silent requests are never stored in history, regardless of store_history.
+            let store_history = !silent
+                && message.get_content()["store_history"]
+                    .as_bool()
+                    .unwrap_or(true);
+            let execution_count = {
+                let mut execution_count = self.execution_count.lock().await;
+                if store_history {
+                    *execution_count += 1;
+                }
+                *execution_count
+            };
+            if !silent {
+                let src = message.code().to_owned();
+                message
+                    .new_message("execute_input")
+                    .with_content(object! {
+                        "execution_count" => execution_count,
+                        "code" => src
+                    })
+                    .send(&mut *self.iopub.lock().await)
+                    .await?;
+            }
 
-            let context = Arc::clone(context);
+            let eval_context = Arc::clone(context);
             let server = self.clone();
-            let (eval_result, message) = tokio::task::spawn_blocking(move || {
-                let eval_result = context.lock().unwrap().execute_with_callbacks(
+            let (eval_result, duration, message) = tokio::task::spawn_blocking(move || {
+                let start = Instant::now();
+                let eval_result = eval_context.lock().unwrap().execute_with_callbacks(
                     message.code(),
                     &mut evcxr::EvalCallbacks {
                         input_reader: &|input_request| {
@@ -218,75 +301,157 @@ impl Server {
                         },
                     },
                 );
-                (eval_result, message)
+                (eval_result, start.elapsed(), message)
             })
             .await?;
             match eval_result {
                 Ok(output) => {
-                    if !output.is_empty() {
-                        // Increase the odds that stdout will have been finished being sent. A
-                        // less hacky alternative would be to add a print statement, then block
-                        // waiting for it.
-                        tokio::time::sleep(Duration::from_millis(1)).await;
-                        let mut data = HashMap::new();
-                        // At the time of writing the json crate appears to have a generic From
-                        // implementation for a Vec<T> where T implements Into<JsonValue>. It also
-                        // has conversion from HashMap<String, JsonValue>, but it doesn't have
-                        // conversion from HashMap<String, T>. Perhaps send a PR? For now, we
-                        // convert the values manually.
-                        for (k, v) in output.content_by_mime_type {
-                            if k.contains("json") {
-                                data.insert(k, json::parse(&v).unwrap_or_else(|_| json::from(v)));
-                            } else {
-                                data.insert(k, json::from(v));
-                            }
-                        }
+                    let metadata = timing_metadata(duration, &output.phases);
+                    let payload = reply_payload(&output);
+                    if !silent {
+                        self.emit_execute_results(output, &message, execution_count, &metadata)
+                            .await?;
+                    }
+                    let user_expressions = self
+                        .evaluate_user_expressions(&message, context, execution_count)
+                        .await?;
+                    execution_reply_sender.send(
                         message
-                            .new_message("execute_result")
+                            .new_reply()
                             .with_content(object! {
+                                "status" => "ok",
                                 "execution_count" => execution_count,
-                                "data" => data,
-                                "metadata" => object!(),
+                                "payload" => payload,
+                                "user_expressions" => user_expressions,
                             })
-                            .send(&mut *self.iopub.lock().await)
+                            .with_metadata(metadata),
+                    )?;
+                }
+                Err(errors) => {
+                    if !silent {
+                        self.emit_errors(&errors, &message, message.code(), execution_count)
                             .await?;
                     }
-                    if let Some(duration) = output.timing {
-                        // TODO replace by duration.as_millis() when stable
-                        let ms = duration.as_secs() * 1000 + u64::from(duration.subsec_millis());
-                        let mut data: HashMap<String, JsonValue> = HashMap::new();
-                        data.insert(
-                            "text/html".into(),
-                            json::from(format!(
-                                "<span style=\"color: rgba(0,0,0,0.4);\">Took {}ms</span>",
-                                ms
-                            )),
-                        );
+                    execution_reply_sender.send(
                         message
-                            .new_message("execute_result")
+                            .new_reply()
                             .with_content(object! {
-                                "execution_count" => execution_count,
-                                "data" => data,
-                                "metadata" => object!(),
+                                "status" => "error",
+                                "execution_count" => execution_count
                             })
-                            .send(&mut *self.iopub.lock().await)
-                            .await?;
-                    }
-                    execution_reply_sender.send(message.new_reply().with_content(object! {
-                        "status" => "ok",
-                        "execution_count" => execution_count,
-                    }))?;
-                }
-                Err(errors) => {
-                    self.emit_errors(&errors, &message, message.code(), execution_count)
-                        .await?;
-                    execution_reply_sender.send(message.new_reply().with_content(object! {
-                        "status" => "error",
-                        "execution_count" => execution_count
-                    }))?;
+                            .with_metadata(timing_metadata(duration, &[])),
+                    )?;
                 }
             };
+            let variable_comms: Vec<String> =
+                self.variable_comms.lock().await.iter().cloned().collect();
+            if !variable_comms.is_empty() {
+                self.send_variables(variable_comms, &message, context)
+                    .await?;
+            }
+        }
+    }
+
+    async fn emit_execute_results(
+        &self,
+        output: evcxr::EvalOutputs,
+        message: &JupyterMessage,
+        execution_count: u32,
+        metadata: &JsonValue,
+    ) -> Result<()> {
+        if !output.is_empty() {
+            // Increase the odds that stdout will have been finished being sent. A
+            // less hacky alternative would be to add a print statement, then block
+            // waiting for it.
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            let data = output_data(output.content_by_mime_type);
+            message
+                .new_message("execute_result")
+                .with_content(object! {
+                    "execution_count" => execution_count,
+                    "data" => data,
+                    "metadata" => metadata.clone(),
+                })
+                .send(&mut *self.iopub.lock().await)
+                .await?;
+        }
+        for warning in &output.warnings {
+            message
+                .new_message("display_data")
+                .with_content(object! {
+                    "data" => warning_data(warning, message.code(), execution_count),
+                    "metadata" => object!(),
+                    "transient" => object!(),
+                })
+                .send(&mut *self.iopub.lock().await)
+                .await?;
+        }
+        // Timing is always available in metadata. It's only displayed if the user asked for it with
+        // :timing. We use display_data rather than a second execute_result, since there can only
+        // be one result per execution.
+        if let Some(duration) = output.timing {
+            message
+                .new_message("display_data")
+                .with_content(object! {
+                    "data" => timing_data(duration),
+                    "metadata" => object!(),
+                    "transient" => object!(),
+                })
+                .send(&mut *self.iopub.lock().await)
+                .await?;
+        }
+        if let Some(memory) = output.memory {
+            message
+                .new_message("display_data")
+                .with_content(object! {
+                    "data" => memory_data(&memory),
+                    "metadata" => object!(),
+                    "transient" => object!(),
+                })
+                .send(&mut *self.iopub.lock().await)
+                .await?;
         }
+        Ok(())
+    }
+
+    /// Evaluates the user_expressions of an execute_request, returning the results keyed by name.
+    /// The expressions can't change the state of the session.
+    async fn evaluate_user_expressions(
+        &self,
+        message: &JupyterMessage,
+        context: &Arc<std::sync::Mutex<CommandContext>>,
+        execution_count: u32,
+    ) -> Result<JsonValue> {
+        let expressions: Vec<(String, String)> = message.get_content()["user_expressions"]
+            .entries()
+            .filter_map(|(name, expression)| {
+                Some((name.to_owned(), expression.as_str()?.to_owned()))
+            })
+            .collect();
+        let context = Arc::clone(context);
+        Ok(tokio::task::spawn_blocking(move || {
+            let mut results = JsonValue::new_object();
+            for (name, expression) in expressions {
+                results[name.as_str()] = match context.lock().unwrap().eval_readonly(&expression) {
+                    Ok(output) => object! {
+                        "status" => "ok",
+                        "data" => output_data(output.content_by_mime_type),
+                        "metadata" => object!(),
+                    },
+                    Err(error) => {
+                        let mut content = error_contents(&error, &expression, execution_count)
+                            .into_iter()
+                            .next()
+                            .unwrap_or_else(JsonValue::new_object);
+                        content.remove("execution_count");
+                        content["status"] = "error".into();
+                        content
+                    }
+                };
+            }
+            results
+        })
+        .await?)
     }
 
     async fn request_input(
@@ -323,17 +488,68 @@ impl Server {
     ) -> Result<()> {
         loop {
             let message = JupyterMessage::read(&mut connection).await?;
-            self.handle_shell_message(
-                message,
-                &mut connection,
-                execution_channel,
-                execution_reply_receiver,
-                &context,
-            )
-            .await?;
+            let abort_queued_requests = self
+                .handle_shell_message(
+                    message,
+                    &mut connection,
+                    execution_channel,
+                    execution_reply_receiver,
+                    &context,
+                )
+                .await?;
+            if !abort_queued_requests {
+                continue;
+            }
+            // Execute requests that were queued behind the one that failed are aborted. Those
+            // are the requests that have already been received, which we find by taking every
+            // message that's ready without waiting (a zero timeout still polls the read once).
+            // Everything else is handled as normal.
+            let mut queued = Vec::new();
+            while let Ok(message) =
+                tokio::time::timeout(Duration::ZERO, JupyterMessage::read(&mut connection)).await
+            {
+                queued.push(message?);
+            }
+            for message in queued {
+                if message.message_type() == "execute_request" {
+                    self.abort_execute_request(message, &mut connection).await?;
+                } else {
+                    self.handle_shell_message(
+                        message,
+                        &mut connection,
+                        execution_channel,
+                        execution_reply_receiver,
+                        &context,
+                    )
+                    .await?;
+                }
+            }
         }
     }
 
+    async fn abort_execute_request<S: zeromq::SocketRecv + zeromq::SocketSend>(
+        &self,
+        message: JupyterMessage,
+        connection: &mut Connection<S>,
+    ) -> Result<()> {
+        message
+            .new_message("status")
+            .with_content(object! {"execution_state" => "busy"})
+            .send(&mut *self.iopub.lock().await)
+            .await?;
+        message
+            .new_reply()
+            .with_content(object! {"status" => "aborted"})
+            .send(connection)
+            .await?;
+        message
+            .new_message("status")
+            .with_content(object! {"execution_state" => "idle"})
+            .send(&mut *self.iopub.lock().await)
+            .await
+    }
+
+    /// Returns whether execute requests that are already queued should be aborted.
     async fn handle_shell_message<S: zeromq::SocketRecv + zeromq::SocketSend>(
         &self,
         message: JupyterMessage,
@@ -341,7 +557,8 @@ impl Server {
         execution_channel: &tokio::sync::mpsc::UnboundedSender<JupyterMessage>,
         execution_reply_receiver: &mut tokio::sync::mpsc::UnboundedReceiver<JupyterMessage>,
         context: &Arc<std::sync::Mutex<CommandContext>>,
-    ) -> Result<()> {
+    ) -> Result<bool> {
+        let mut abort_queued_requests = false;
         // Processing of every message should be enclosed between "busy" and "idle"
         // see https://jupyter-client.readthedocs.io/en/latest/messaging.html#messages-on-the-shell-router-dealer-channel
         // Jupiter Lab doesn't use the kernel until it received "idle" for kernel_info_request
@@ -366,16 +583,71 @@ impl Server {
                 .send(connection)
                 .await?;
         } else if message.message_type() == "execute_request" {
+            let stop_on_error = message.get_content()["stop_on_error"]
+                .as_bool()
+                .unwrap_or(true);
             execution_channel.send(message)?;
             if let Some(reply) = execution_reply_receiver.recv().await {
+                abort_queued_requests = stop_on_error && reply.get_content()["status"] == "error";
                 reply.send(connection).await?;
             }
         } else if message.message_type() == "comm_open" {
-            comm_open(message, context, Arc::clone(&self.iopub)).await?;
-        } else if message.message_type() == "comm_msg"
-            || message.message_type() == "comm_info_request"
-        {
-            // We don't handle this yet.
+            if message.target_name() == "evcxr-cargo-check" {
+                cargo_check_comm_open(message, context, Arc::clone(&self.iopub));
+            } else if message.target_name() == "evcxr-rustfmt" {
+                rustfmt_comm_open(message, context, Arc::clone(&self.iopub));
+            } else if message.target_name() == VARIABLES_TARGET {
+                let comm_id = message.comm_id().to_owned();
+                self.variable_comms.lock().await.insert(comm_id.clone());
+                self.send_variables(vec![comm_id], &message, context)
+                    .await?;
+            } else {
+                // Anything else might be handled by user code. If it's not, then user code will
+                // close the comm.
+                self.user_comms.lock().await.insert(
+                    message.comm_id().to_owned(),
+                    message.target_name().to_owned(),
+                );
+                let _ = self.forwarded_comm_messages.send(("open", message));
+            }
+        } else if message.message_type() == "comm_msg" {
+            // Any message sent to a variables comm is treated as a request to refresh.
+            if self.variable_comms.lock().await.contains(message.comm_id()) {
+                let comm_id = message.comm_id().to_owned();
+                self.send_variables(vec![comm_id], &message, context)
+                    .await?;
+            } else if self.user_comms.lock().await.contains_key(message.comm_id()) {
+                let _ = self.forwarded_comm_messages.send(("msg", message));
+            }
+        } else if message.message_type() == "comm_close" {
+            self.variable_comms.lock().await.remove(message.comm_id());
+            let removed = self.user_comms.lock().await.remove(message.comm_id());
+            if removed.is_some() {
+                let _ = self.forwarded_comm_messages.send(("close", message));
+            }
+        } else if message.message_type() == "comm_info_request" {
+            let target_name = message.target_name();
+            let mut comms = JsonValue::new_object();
+            let mut open_comms: Vec<(String, String)> = self
+                .user_comms
+                .lock()
+                .await
+                .iter()
+                .map(|(comm_id, target_name)| (comm_id.clone(), target_name.clone()))
+                .collect();
+            for comm_id in self.variable_comms.lock().await.iter() {
+                open_comms.push((comm_id.clone(), VARIABLES_TARGET.to_owned()));
+            }
+            for (comm_id, comm_target_name) in open_comms {
+                if target_name.is_empty() || target_name == comm_target_name {
+                    comms[comm_id.as_str()] = object! {"target_name" => comm_target_name};
+                }
+            }
+            message
+                .new_reply()
+                .with_content(object! {"status" => "ok", "comms" => comms})
+                .send(connection)
+                .await?;
         } else if message.message_type() == "complete_request" {
             let reply = message.new_reply().with_content(
                 match handle_completion_request(context, message).await {
@@ -398,13 +670,88 @@ impl Server {
             );
         }
         idle.send(&mut *self.iopub.lock().await).await?;
+        Ok(abort_queued_requests)
+    }
+
+    /// Sends information about all variables to each of the specified evcxr-variables comms.
+    async fn send_variables(
+        &self,
+        comm_ids: Vec<String>,
+        parent_message: &JupyterMessage,
+        context: &Arc<std::sync::Mutex<CommandContext>>,
+    ) -> Result<()> {
+        let context = Arc::clone(context);
+        let (variables, previews) = tokio::task::spawn_blocking(move || {
+            let mut context = context.lock().unwrap();
+            // If previews can't be obtained, we still send everything else.
+            let previews = context
+                .variable_previews(MAX_VARIABLE_PREVIEW_CHARS)
+                .unwrap_or_default();
+            (context.variables(), previews)
+        })
+        .await?;
+        let data = object! {
+            "variables" => variables.into_iter().map(|variable| {
+                let preview = previews.get(&variable.name).cloned();
+                object! {
+                    "name" => variable.name,
+                    "type" => variable.type_name,
+                    "mutable" => variable.is_mut,
+                    "preview" => preview,
+                }
+            }).collect::<Vec<_>>(),
+        };
+        for comm_id in comm_ids {
+            parent_message
+                .new_message("comm_msg")
+                .with_content(object! {
+                    "comm_id" => comm_id,
+                    "data" => data.clone(),
+                })
+                .send(&mut *self.iopub.lock().await)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Passes a comm message from the frontend to user code. Anything displayed by the handler in
+    /// user code is sent as display_data.
+    async fn forward_comm_message(
+        &self,
+        message_type: &str,
+        message: JupyterMessage,
+        context: &Arc<std::sync::Mutex<CommandContext>>,
+    ) -> Result<()> {
+        let mut content = message.get_content().clone();
+        content["type"] = message_type.into();
+        let context = Arc::clone(context);
+        let result = tokio::task::spawn_blocking(move || {
+            context.lock().unwrap().handle_comm_message(&content.dump())
+        })
+        .await?;
+        match result {
+            Ok(output) => {
+                if !output.is_empty() {
+                    message
+                        .new_message("display_data")
+                        .with_content(object! {
+                            "data" => output_data(output.content_by_mime_type),
+                            "metadata" => object!(),
+                        })
+                        .send(&mut *self.iopub.lock().await)
+                        .await?;
+                }
+            }
+            Err(error) => eprintln!("Error handling comm message: {error}"),
+        }
         Ok(())
     }
 
     async fn handle_control(
         mut self,
         mut connection: Connection<zeromq::RouterSocket>,
-        process_handle: Arc<std::sync::Mutex<std::process::Child>>,
+        interrupt_handle: evcxr::InterruptHandle,
+        context: Arc<std::sync::Mutex<CommandContext>>,
     ) -> Result<()> {
         loop {
             let message = JupyterMessage::read(&mut connection).await?;
@@ -416,15 +763,23 @@ impl Server {
                         .send(&mut connection)
                         .await?
                 }
-                "shutdown_request" => self.signal_shutdown().await,
+                "shutdown_request" => {
+                    let restart = message.get_content()["restart"].as_bool().unwrap_or(false);
+                    if restart {
+                        self.restart(&interrupt_handle, &context).await?;
+                    }
+                    message
+                        .new_reply()
+                        .with_content(object! {"status" => "ok", "restart" => restart})
+                        .send(&mut connection)
+                        .await?;
+                    if !restart {
+                        self.signal_shutdown().await;
+                    }
+                }
                 "interrupt_request" => {
-                    let process_handle = process_handle.clone();
-                    tokio::task::spawn_blocking(move || {
-                        if let Err(error) = process_handle.lock().unwrap().kill() {
-                            eprintln!("Failed to restart subprocess: {}", error);
-                        }
-                    })
-                    .await?;
+                    let interrupt_handle = interrupt_handle.clone();
+                    tokio::task::spawn_blocking(move || interrupt_handle.interrupt()).await?;
                     message.new_reply().send(&mut connection).await?;
                 }
                 _ => {
@@ -437,9 +792,38 @@ impl Server {
         }
     }
 
+    /// Restores the kernel to its initial state, except that the compilation directory is kept, so
+    /// that dependencies don't all need to be rebuilt.
+    async fn restart(
+        &self,
+        interrupt_handle: &evcxr::InterruptHandle,
+        context: &Arc<std::sync::Mutex<CommandContext>>,
+    ) -> Result<()> {
+        // Stop anything that's running, otherwise we'd have to wait for it to finish before we
+        // could lock the context.
+        let interrupt_handle = interrupt_handle.clone();
+        tokio::task::spawn_blocking(move || interrupt_handle.interrupt()).await?;
+        let context = Arc::clone(context);
+        let kernel_options = Arc::clone(&self.kernel_options);
+        let result = tokio::task::spawn_blocking(move || {
+            let mut context = context.lock().unwrap();
+            context.restart()?;
+            kernel_options.configure(&mut context)
+        })
+        .await?;
+        if let Err(error) = result {
+            eprintln!("Error while restarting: {error}");
+        }
+        // Comms handled by user code went away with the subprocess.
+        self.user_comms.lock().await.clear();
+        *self.execution_count.lock().await = INITIAL_EXECUTION_COUNT;
+        Ok(())
+    }
+
     async fn start_output_pass_through_thread(
         self,
         channels: Vec<(&'static str, crossbeam_channel::Receiver<String>)>,
+        comm_recv: crossbeam_channel::Receiver<String>,
         shutdown_recv: crossbeam_channel::Receiver<()>,
     ) {
         tokio::task::spawn_blocking(move || {
@@ -447,12 +831,25 @@ impl Server {
             for (_, channel) in &channels {
                 select.recv(channel);
             }
+            let comm_index = select.recv(&comm_recv);
             let shutdown_index = select.recv(&shutdown_recv);
             loop {
                 let index = select.ready();
                 if index == shutdown_index {
                     return;
                 }
+                if index == comm_index {
+                    if let Ok(message) = comm_recv.try_recv() {
+                        // Comm messages are sent in order, since e.g. a comm_msg sent before the
+                        // corresponding comm_open would be dropped by the frontend.
+                        if let Err(error) =
+                            self.tokio_handle.block_on(self.pass_comm_message(&message))
+                        {
+                            eprintln!("comm error: {error}");
+                        }
+                    }
+                    continue;
+                }
                 let (output_name, channel) = &channels[index];
                 // Needed in order to make the borrow checker happy.
                 let output_name: &'static str = output_name;
@@ -476,7 +873,10 @@ impl Server {
     async fn pass_output_line(&self, output_name: &'static str, line: String) {
         let mut message = None;
         if let Some(exec_request) = &*self.latest_execution_request.lock().await {
-            message = Some(exec_request.new_message("stream"));
+            // Silent requests don't produce any output.
+            if exec_request.get_content()["silent"].as_bool() != Some(true) {
+                message = Some(exec_request.new_message("stream"));
+            }
         }
         if let Some(message) = message {
             if let Err(error) = message
@@ -492,6 +892,47 @@ impl Server {
         }
     }
 
+    /// Sends a comm message from user code to the frontend.
+    async fn pass_comm_message(&self, message: &str) -> Result<()> {
+        let mut content = json::parse(message)?;
+        let message_type = content.remove("type");
+        let metadata = content.remove("metadata");
+        let comm_id = content["comm_id"].as_str().unwrap_or("").to_owned();
+        if !content.has_key("data") {
+            content["data"] = JsonValue::new_object();
+        }
+        let message_type = match message_type.as_str() {
+            Some("open") => {
+                let target_name = content["target_name"].as_str().unwrap_or("").to_owned();
+                self.user_comms.lock().await.insert(comm_id, target_name);
+                "comm_open"
+            }
+            Some("msg") => "comm_msg",
+            Some("close") => {
+                self.user_comms.lock().await.remove(&comm_id);
+                "comm_close"
+            }
+            _ => bail!("Invalid comm message: {message}"),
+        };
+        let mut jupyter_message = None;
+        if let Some(exec_request) = &*self.latest_execution_request.lock().await {
+            jupyter_message = Some(exec_request.new_message(message_type));
+        }
+        if let Some(jupyter_message) = jupyter_message {
+            let metadata = if metadata.is_object() {
+                metadata
+            } else {
+                JsonValue::new_object()
+            };
+            jupyter_message
+                .with_content(content)
+                .with_metadata(metadata)
+                .send(&mut *self.iopub.lock().await)
+                .await?;
+        }
+        Ok(())
+    }
+
     async fn emit_errors(
         &self,
         errors: &evcxr::Error,
@@ -499,88 +940,249 @@ impl Server {
         source: &str,
         execution_count: u32,
     ) -> Result<()> {
-        match errors {
-            evcxr::Error::CompilationErrors(errors) => {
-                for error in errors {
-                    let message = format!("{}", error.message().bright_red());
-                    if error.is_from_user_code() {
-                        let file_name = format!("command_{}", execution_count);
-                        let mut traceback = Vec::new();
-                        if let Some(report) =
-                            error.build_report(file_name.clone(), source.to_string(), Theme::Light)
-                        {
-                            let mut s = Vec::new();
-                            report
-                                .write(sources([(file_name, source.to_string())]), &mut s)
-                                .unwrap();
-                            let s = String::from_utf8_lossy(&s);
-                            traceback = s.lines().map(|x| x.to_string()).collect::<Vec<_>>();
-                        } else {
-                            for spanned_message in error.spanned_messages() {
-                                for line in &spanned_message.lines {
-                                    traceback.push(line.clone());
+        for (message_type, content) in error_messages(errors, source, execution_count) {
+            parent_message
+                .new_message(message_type)
+                .with_content(content)
+                .send(&mut *self.iopub.lock().await)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Converts the outputs of an evaluation into the form used for the data of an execute_result.
+pub(crate) fn output_data(
+    content_by_mime_type: HashMap<String, String>,
+) -> HashMap<String, JsonValue> {
+    let mut data = HashMap::new();
+    // At the time of writing the json crate appears to have a generic From
+    // implementation for a Vec<T> where T implements Into<JsonValue>. It also
+    // has conversion from HashMap<String, JsonValue>, but it doesn't have
+    // conversion from HashMap<String, T>. Perhaps send a PR? For now, we
+    // convert the values manually.
+    for (k, v) in content_by_mime_type {
+        if k.contains("json") {
+            data.insert(k, json::parse(&v).unwrap_or_else(|_| json::from(v)));
+        } else {
+            data.insert(k, json::from(v));
+        }
+    }
+    data
+}
+
+/// Returns the payload of the execute_reply for a successful execution. See
+/// https://jupyter-client.readthedocs.io/en/stable/messaging.html#payloads-deprecated
+fn reply_payload(output: &evcxr::EvalOutputs) -> JsonValue {
+    let mut payload = array![];
+    if let Some(next_input) = &output.next_input {
+        payload
+            .push(object! {
+                "source" => "set_next_input",
+                "text" => next_input.as_str(),
+                "replace" => false,
+            })
+            .unwrap();
+    }
+    payload
+}
+
+/// Returns the data of the display_data message that we use to report a compiler warning in
+/// `source`.
+pub(crate) fn warning_data(
+    warning: &evcxr::CompilationError,
+    source: &str,
+    execution_count: u32,
+) -> JsonValue {
+    let file_name = format!("command_{}", execution_count);
+    let text = match warning.build_report(file_name.clone(), source.to_string(), Theme::Light) {
+        Some(report) => {
+            let mut s = Vec::new();
+            report
+                .write(sources([(file_name, source.to_string())]), &mut s)
+                .unwrap();
+            String::from_utf8_lossy(&s).into_owned()
+        }
+        None => warning.rendered(),
+    };
+    object! {
+        "text/html" => warning.build_html_report(source),
+        "text/plain" => text,
+    }
+}
+
+/// Returns the data of the execute_result that we use to report how long an evaluation took.
+pub(crate) fn timing_data(duration: Duration) -> HashMap<String, JsonValue> {
+    // TODO replace by duration.as_millis() when stable
+    let ms = duration.as_secs() * 1000 + u64::from(duration.subsec_millis());
+    let mut data: HashMap<String, JsonValue> = HashMap::new();
+    data.insert(
+        "text/html".into(),
+        json::from(format!(
+            "<span style=\"color: rgba(0,0,0,0.4);\">Took {}ms</span>",
+            ms
+        )),
+    );
+    data
+}
+
+/// Returns the data of the display_data message that we use to report how an evaluation affected
+/// memory usage.
+pub(crate) fn memory_data(memory: &evcxr::MemoryUsage) -> HashMap<String, JsonValue> {
+    let mut data: HashMap<String, JsonValue> = HashMap::new();
+    data.insert(
+        "text/html".into(),
+        json::from(format!(
+            "<span style=\"color: rgba(0,0,0,0.4);\">{}</span>",
+            memory
+        )),
+    );
+    data
+}
+
+/// Returns metadata describing how long an evaluation took, both in total and for each phase of
+/// compilation and execution. This is attached to execute_reply and execute_result messages so
+/// that tools can collect timings without them being displayed.
+pub(crate) fn timing_metadata(duration: Duration, phases: &[evcxr::PhaseDetails]) -> JsonValue {
+    let phases: Vec<JsonValue> = phases
+        .iter()
+        .map(|phase| {
+            object! {
+                "name" => phase.name.as_str(),
+                "duration_ms" => duration_ms(phase.duration),
+            }
+        })
+        .collect();
+    object! {
+        "evcxr" => object! {
+            "timing" => object! {
+                "duration_ms" => duration_ms(duration),
+                "phases" => phases,
+            },
+        },
+    }
+}
+
+fn duration_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Returns the type and content of each message used to report `errors`. Compilation errors in
+/// user code are reported as HTML via display_data, with the plain text report as a fallback. The
+/// traceback of the error message that follows is then just the error's message, so that the
+/// report isn't shown twice.
+pub(crate) fn error_messages(
+    errors: &evcxr::Error,
+    source: &str,
+    execution_count: u32,
+) -> Vec<(&'static str, JsonValue)> {
+    let contents = error_contents(errors, source, execution_count);
+    let evcxr::Error::CompilationErrors(compilation_errors) = errors else {
+        return contents.into_iter().map(|c| ("error", c)).collect();
+    };
+    let mut messages = Vec::new();
+    // error_contents returns one content for each compilation error.
+    for (error, mut content) in compilation_errors.iter().zip(contents) {
+        if error.is_from_user_code() {
+            let plain_text: Vec<&str> = content["traceback"]
+                .members()
+                .filter_map(JsonValue::as_str)
+                .collect();
+            messages.push((
+                "display_data",
+                object! {
+                    "data" => object! {
+                        "text/html" => error.build_html_report(source),
+                        "text/plain" => plain_text.join("\n"),
+                    },
+                    "metadata" => object!(),
+                    "transient" => object!(),
+                },
+            ));
+            content["traceback"] = array![format!("{}", error.message().bright_red())];
+        }
+        messages.push(("error", content));
+    }
+    messages
+}
+
+/// Returns the content (ename, evalue and traceback) of an error message for each of the errors
+/// in `errors`.
+pub(crate) fn error_contents(
+    errors: &evcxr::Error,
+    source: &str,
+    execution_count: u32,
+) -> Vec<JsonValue> {
+    let mut contents = Vec::new();
+    match errors {
+        evcxr::Error::CompilationErrors(errors) => {
+            for error in errors {
+                let message = format!("{}", error.message().bright_red());
+                if error.is_from_user_code() {
+                    let file_name = format!("command_{}", execution_count);
+                    let mut traceback = Vec::new();
+                    if let Some(report) =
+                        error.build_report(file_name.clone(), source.to_string(), Theme::Light)
+                    {
+                        let mut s = Vec::new();
+                        report
+                            .write(sources([(file_name, source.to_string())]), &mut s)
+                            .unwrap();
+                        let s = String::from_utf8_lossy(&s);
+                        traceback = s.lines().map(|x| x.to_string()).collect::<Vec<_>>();
+                    } else {
+                        for spanned_message in error.spanned_messages() {
+                            for line in &spanned_message.lines {
+                                traceback.push(line.clone());
+                            }
+                            if let Some(span) = &spanned_message.span {
+                                let mut carrots = String::new();
+                                for _ in 1..span.start_column {
+                                    carrots.push(' ');
                                 }
-                                if let Some(span) = &spanned_message.span {
-                                    let mut carrots = String::new();
-                                    for _ in 1..span.start_column {
-                                        carrots.push(' ');
-                                    }
-                                    for _ in span.start_column..span.end_column {
-                                        carrots.push('^');
-                                    }
-                                    traceback.push(format!(
-                                        "{} {}",
-                                        carrots.bright_red(),
-                                        spanned_message.label.bright_blue()
-                                    ));
-                                } else {
-                                    traceback.push(spanned_message.label.clone());
+                                for _ in span.start_column..span.end_column {
+                                    carrots.push('^');
                                 }
-                            }
-                            traceback.push(error.message());
-                            for help in error.help() {
-                                traceback.push(format!("{}: {}", "help".bold(), help));
+                                traceback.push(format!(
+                                    "{} {}",
+                                    carrots.bright_red(),
+                                    spanned_message.label.bright_blue()
+                                ));
+                            } else {
+                                traceback.push(spanned_message.label.clone());
                             }
                         }
-                        parent_message
-                            .new_message("error")
-                            .with_content(object! {
-                                "ename" => "Error",
-                                "evalue" => error.message(),
-                                "traceback" => traceback,
-                            })
-                            .send(&mut *self.iopub.lock().await)
-                            .await?;
-                    } else {
-                        parent_message
-                            .new_message("error")
-                            .with_content(object! {
-                                "ename" => "Error",
-                                "evalue" => error.message(),
-                                "traceback" => array![
-                                    message
-                                ],
-                            })
-                            .send(&mut *self.iopub.lock().await)
-                            .await?;
+                        traceback.push(error.message());
+                        for help in error.help() {
+                            traceback.push(format!("{}: {}", "help".bold(), help));
+                        }
                     }
-                }
-            }
-            error => {
-                let displayed_error = format!("{}", error);
-                parent_message
-                    .new_message("error")
-                    .with_content(object! {
+                    contents.push(object! {
                         "ename" => "Error",
-                        "evalue" => displayed_error.clone(),
-                        "traceback" => array![displayed_error],
-                    })
-                    .send(&mut *self.iopub.lock().await)
-                    .await?;
+                        "evalue" => error.message(),
+                        "traceback" => traceback,
+                    });
+                } else {
+                    contents.push(object! {
+                        "ename" => "Error",
+                        "evalue" => error.message(),
+                        "traceback" => array![
+                            message
+                        ],
+                    });
+                }
             }
         }
-        Ok(())
+        error => {
+            let displayed_error = format!("{}", error);
+            contents.push(object! {
+                "ename" => "Error",
+                "evalue" => displayed_error.clone(),
+                "traceback" => array![displayed_error],
+            });
+        }
     }
+    contents
 }
 
 impl ShutdownReceiver {
@@ -589,47 +1191,90 @@ impl ShutdownReceiver {
     }
 }
 
-async fn comm_open(
+const VARIABLES_TARGET: &str = "evcxr-variables";
+
+fn cargo_check_comm_open(
     message: JupyterMessage,
     context: &Arc<std::sync::Mutex<CommandContext>>,
     iopub: Arc<Mutex<Connection<zeromq::PubSocket>>>,
-) -> Result<()> {
-    if message.target_name() == "evcxr-cargo-check" {
-        let context = Arc::clone(context);
-        tokio::spawn(async move {
-            if let Some(code) = message.data()["code"].as_str() {
-                let data = cargo_check(code.to_owned(), context).await;
-                let response_content = object! {
-                    "comm_id" => message.comm_id(),
-                    "data" => data,
-                };
-                message
-                    .new_message("comm_msg")
-                    .without_parent_header()
-                    .with_content(response_content)
-                    .send(&mut *iopub.lock().await)
-                    .await
-                    .unwrap();
-            }
+) {
+    let context = Arc::clone(context);
+    tokio::spawn(async move {
+        if let Some(code) = message.data()["code"].as_str() {
+            let clippy = message.data()["clippy"].as_bool().unwrap_or(false);
+            let data = cargo_check(code.to_owned(), clippy, context).await;
+            let response_content = object! {
+                "comm_id" => message.comm_id(),
+                "data" => data,
+            };
             message
-                .comm_close_message()
+                .new_message("comm_msg")
+                .without_parent_header()
+                .with_content(response_content)
                 .send(&mut *iopub.lock().await)
                 .await
                 .unwrap();
-        });
-        Ok(())
-    } else {
-        // Unrecognised comm target, just close the comm.
+        }
         message
             .comm_close_message()
             .send(&mut *iopub.lock().await)
             .await
-    }
+            .unwrap();
+    });
 }
 
-async fn cargo_check(code: String, context: Arc<std::sync::Mutex<CommandContext>>) -> JsonValue {
+/// Formats the code sent when opening an evcxr-rustfmt comm, replying with either the formatted
+/// code or an error, then closing the comm.
+fn rustfmt_comm_open(
+    message: JupyterMessage,
+    context: &Arc<std::sync::Mutex<CommandContext>>,
+    iopub: Arc<Mutex<Connection<zeromq::PubSocket>>>,
+) {
+    let context = Arc::clone(context);
+    tokio::spawn(async move {
+        if let Some(code) = message.data()["code"].as_str() {
+            let code = code.to_owned();
+            let result =
+                tokio::task::spawn_blocking(move || context.lock().unwrap().rustfmt(&code)).await;
+            let data = match result {
+                Ok(Ok(formatted)) => object! {"formatted" => formatted},
+                Ok(Err(error)) => object! {"error" => error.to_string()},
+                Err(error) => object! {"error" => error.to_string()},
+            };
+            let response_content = object! {
+                "comm_id" => message.comm_id(),
+                "data" => data,
+            };
+            message
+                .new_message("comm_msg")
+                .without_parent_header()
+                .with_content(response_content)
+                .send(&mut *iopub.lock().await)
+                .await
+                .unwrap();
+        }
+        message
+            .comm_close_message()
+            .send(&mut *iopub.lock().await)
+            .await
+            .unwrap();
+    });
+}
+
+/// Checks `code`, returning any problems found in a form suitable for sending to the frontend. If
+/// `clippy` is true, then Clippy is used, so lints are also reported.
+async fn cargo_check(
+    code: String,
+    clippy: bool,
+    context: Arc<std::sync::Mutex<CommandContext>>,
+) -> JsonValue {
     let problems = tokio::task::spawn_blocking(move || {
-        context.lock().unwrap().check(&code).unwrap_or_default()
+        let mut context = context.lock().unwrap();
+        if clippy {
+            context.clippy(&code).unwrap_or_default()
+        } else {
+            context.check(&code).unwrap_or_default()
+        }
     })
     .await
     .unwrap_or_default();
@@ -669,10 +1314,15 @@ async fn bind_socket<S: zeromq::Socket>(
     config: &control_file::Control,
     port: u16,
 ) -> Result<Connection<S>> {
-    let endpoint = format!("{}://{}:{}", config.transport, config.ip, port);
+    let endpoint = match config.transport.as_str() {
+        "tcp" => format!("tcp://{}:{}", config.ip, port),
+        // Jupyter names IPC sockets by appending the "port" to the path given in "ip".
+        "ipc" if cfg!(unix) => format!("ipc://{}-{}", config.ip, port),
+        transport => bail!("Unsupported transport '{}'", transport),
+    };
     let mut socket = S::new();
     socket.bind(&endpoint).await?;
-    Connection::new(socket, &config.key)
+    Connection::new(socket, config.signature_scheme, &config.key)
 }
 
 /// See [Kernel info documentation](https://jupyter-client.readthedocs.io/en/stable/messaging.html#kernel-info)
@@ -9,6 +9,7 @@
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Result;
+use json::JsonValue;
 use std::env;
 use std::fs;
 use std::io::Write;
@@ -24,47 +25,133 @@ const LINT_CSS: &[u8] = include_bytes!("../third_party/CodeMirror/addons/lint/li
 const LINT_LICENSE: &[u8] = include_bytes!("../third_party/CodeMirror/LICENSE");
 const VERSION_TXT: &[u8] = include_bytes!("../client/version.txt");
 
-pub(crate) fn install() -> Result<()> {
-    let kernel_dir = get_kernel_dir()?;
+const DEFAULT_KERNEL_NAME: &str = "rust";
+
+/// Where kernels get installed. See
+/// https://jupyter-client.readthedocs.io/en/latest/kernels.html#kernel-specs
+pub(crate) enum KernelLocation {
+    /// The current user's Jupyter data directory, or JUPYTER_PATH if set.
+    User,
+    /// The prefix of the Python environment that Jupyter is installed in, e.g. a virtualenv.
+    SysPrefix,
+    Prefix(PathBuf),
+}
+
+pub(crate) struct InstallOptions {
+    /// The name of the kernel, which is also the name of its directory.
+    pub(crate) name: String,
+    pub(crate) display_name: Option<String>,
+    pub(crate) toolchain: Option<String>,
+    pub(crate) opt_level: Option<String>,
+    /// Environment variables that Jupyter should set when starting the kernel.
+    pub(crate) env: Vec<(String, String)>,
+    pub(crate) location: KernelLocation,
+}
+
+impl Default for InstallOptions {
+    fn default() -> Self {
+        InstallOptions {
+            name: DEFAULT_KERNEL_NAME.to_owned(),
+            display_name: None,
+            toolchain: None,
+            opt_level: None,
+            env: Vec::new(),
+            location: KernelLocation::User,
+        }
+    }
+}
+
+pub(crate) fn install(options: &InstallOptions) -> Result<()> {
+    if options.name.is_empty()
+        || !options
+            .name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+    {
+        bail!(
+            "Invalid kernel name '{}'. Names may only contain ASCII letters, numbers, '.', '_' \
+             and '-'",
+            options.name
+        );
+    }
+    let kernel_dir = get_kernels_dir(&options.location)?.join(&options.name);
     fs::create_dir_all(&kernel_dir)?;
     let current_exe_path = env::current_exe()?;
     let current_exe = current_exe_path
         .to_str()
         .ok_or_else(|| anyhow!("current exe path isn't valid UTF-8"))?;
-    let kernel_json = object! {
-        "argv" => array![current_exe, "--control_file", "{connection_file}"],
-        "display_name" => "Rust",
+    let mut argv = array![current_exe, "--control_file", "{connection_file}"];
+    if let Some(toolchain) = &options.toolchain {
+        argv.push("--toolchain")?;
+        argv.push(toolchain.as_str())?;
+    }
+    if let Some(opt_level) = &options.opt_level {
+        argv.push("--opt")?;
+        argv.push(opt_level.as_str())?;
+    }
+    let display_name = match (&options.display_name, &options.toolchain) {
+        (Some(display_name), _) => display_name.clone(),
+        (None, Some(toolchain)) => format!("Rust ({toolchain})"),
+        (None, None) => "Rust".to_owned(),
+    };
+    let mut kernel_json = object! {
+        "argv" => argv,
+        "display_name" => display_name,
         "language" => "rust",
         "interrupt_mode" => "message",
     };
+    if !options.env.is_empty() {
+        let mut env = JsonValue::new_object();
+        for (key, value) in &options.env {
+            env[key.as_str()] = value.as_str().into();
+        }
+        kernel_json["env"] = env;
+    }
     let kernel_json_filename = kernel_dir.join("kernel.json");
     println!("Writing {}", kernel_json_filename.to_string_lossy());
     kernel_json.write_pretty(&mut fs::File::create(kernel_json_filename)?, 2)?;
-    install_resource(&kernel_dir, "logo-32x32.png", LOGO_32X32)?;
-    install_resource(&kernel_dir, "logo-64x64.png", LOGO_64X64)?;
-    install_resource(&kernel_dir, "logo-LICENSE.md", LOGO_LICENSE)?;
-    install_resource(&kernel_dir, "kernel.js", KERNEL_JS)?;
-    install_resource(&kernel_dir, "lint.js", LINT_JS)?;
-    install_resource(&kernel_dir, "lint.css", LINT_CSS)?;
-    install_resource(&kernel_dir, "lint-LICENSE", LINT_LICENSE)?;
-    install_resource(&kernel_dir, "version.txt", VERSION_TXT)?;
+    install_resources(&kernel_dir)?;
     println!("Installation complete");
     Ok(())
 }
 
-/// Checks if the current installation is out-of-date, by looking at what's in
-/// version.txt. If it is out of date, then updates it.
+fn install_resources(kernel_dir: &Path) -> Result<()> {
+    install_resource(kernel_dir, "logo-32x32.png", LOGO_32X32)?;
+    install_resource(kernel_dir, "logo-64x64.png", LOGO_64X64)?;
+    install_resource(kernel_dir, "logo-LICENSE.md", LOGO_LICENSE)?;
+    install_resource(kernel_dir, "kernel.js", KERNEL_JS)?;
+    install_resource(kernel_dir, "lint.js", LINT_JS)?;
+    install_resource(kernel_dir, "lint.css", LINT_CSS)?;
+    install_resource(kernel_dir, "lint-LICENSE", LINT_LICENSE)?;
+    install_resource(kernel_dir, "version.txt", VERSION_TXT)?;
+    Ok(())
+}
+
+/// Checks if the current installations are out-of-date, by looking at what's in
+/// version.txt. Any that are out of date are updated.
 pub(crate) fn update_if_necessary() -> Result<()> {
-    let kernel_dir = get_kernel_dir()?;
-    // If the kernel directory doesn't exist, then we're probably being run from
-    // a wrapper, so we shouldn't "update", since that would in effect be
-    // installing ourselves when we weren't already installed.
-    if !kernel_dir.exists() {
-        return Ok(());
+    // Only kernels that are already installed get updated. If there aren't any,
+    // then we're probably being run from a wrapper, so we shouldn't "update",
+    // since that would in effect be installing ourselves when we weren't already
+    // installed. Kernels that run a different binary are left for that binary to
+    // update. We don't look in the sys-prefix location, since finding it means
+    // running Python, which we don't want to do every time we start.
+    let current_exe = env::current_exe()?;
+    let mut updated = false;
+    for (kernel_dir, kernel_json) in installed_kernels(kernels_dirs(false)?) {
+        if !runs_exe(&kernel_json, &current_exe) {
+            continue;
+        }
+        let installed_version = std::fs::read(kernel_dir.join("version.txt")).unwrap_or_default();
+        // Kernels installed system-wide can only be updated by reinstalling them.
+        if installed_version != VERSION_TXT && is_writable(&kernel_dir) {
+            // Only resources are updated. kernel.json is left alone, since it may have been
+            // installed with options that we don't know about.
+            install_resources(&kernel_dir)?;
+            updated = true;
+        }
     }
-    let installed_version = std::fs::read(kernel_dir.join("version.txt")).unwrap_or_default();
-    if installed_version != VERSION_TXT {
-        install()?;
+    if updated {
         eprintln!(
             "\n\n==================================================================\n\
             Updated Evcxr Jupyter installation. Note, updates unfortunately \n\
@@ -83,24 +170,136 @@ pub(crate) fn install_resource(dir: &Path, filename: &str, bytes: &'static [u8])
     Ok(())
 }
 
-pub(crate) fn uninstall() -> Result<()> {
-    let kernel_dir = get_kernel_dir()?;
+pub(crate) fn uninstall(options: &InstallOptions) -> Result<()> {
+    let kernel_dir = get_kernels_dir(&options.location)?.join(&options.name);
     println!("Deleting {}", kernel_dir.to_string_lossy());
     fs::remove_dir_all(kernel_dir)?;
     println!("Uninstall complete");
     Ok(())
 }
 
+/// Prints the name, display name and location of each installed evcxr kernel.
+pub(crate) fn list() -> Result<()> {
+    let kernels = installed_kernels(kernels_dirs(true)?);
+    if kernels.is_empty() {
+        println!("No evcxr kernels are installed");
+    }
+    for (kernel_dir, kernel_json) in kernels {
+        println!(
+            "{}: {} ({})",
+            kernel_dir.file_name().unwrap_or_default().to_string_lossy(),
+            kernel_json["display_name"],
+            kernel_dir.to_string_lossy()
+        );
+    }
+    Ok(())
+}
+
+/// Returns the directories that Jupyter looks in for kernels. Finding the sys-prefix location
+/// requires running Python.
+fn kernels_dirs(include_sys_prefix: bool) -> Result<Vec<PathBuf>> {
+    let mut kernels_dirs = vec![get_kernels_dir(&KernelLocation::User)?];
+    // If Python isn't available, then there can't be a sys-prefix installation.
+    if include_sys_prefix {
+        if let Ok(dir) = get_kernels_dir(&KernelLocation::SysPrefix) {
+            kernels_dirs.push(dir);
+        }
+    }
+    if cfg!(unix) {
+        kernels_dirs.push(PathBuf::from("/usr/local/share/jupyter/kernels"));
+        kernels_dirs.push(PathBuf::from("/usr/share/jupyter/kernels"));
+    }
+    kernels_dirs.dedup();
+    Ok(kernels_dirs)
+}
+
+/// Returns the directory and kernel.json of each evcxr kernel installed in `kernels_dirs`.
+fn installed_kernels(kernels_dirs: Vec<PathBuf>) -> Vec<(PathBuf, JsonValue)> {
+    let mut kernels = Vec::new();
+    for kernels_dir in kernels_dirs {
+        let Ok(entries) = fs::read_dir(&kernels_dir) else {
+            continue;
+        };
+        let mut kernel_dirs: Vec<PathBuf> = entries.filter_map(|e| Some(e.ok()?.path())).collect();
+        kernel_dirs.sort();
+        for kernel_dir in kernel_dirs {
+            let Ok(kernel_json) = fs::read_to_string(kernel_dir.join("kernel.json")) else {
+                continue;
+            };
+            let Ok(kernel_json) = json::parse(&kernel_json) else {
+                continue;
+            };
+            if is_evcxr_kernel(&kernel_json) {
+                kernels.push((kernel_dir, kernel_json));
+            }
+        }
+    }
+    kernels
+}
+
+/// Returns whether the kernel runs `exe`.
+fn runs_exe(kernel_json: &JsonValue, exe: &Path) -> bool {
+    let Some(kernel_exe) = kernel_json["argv"][0].as_str() else {
+        return false;
+    };
+    let kernel_exe = Path::new(kernel_exe);
+    kernel_exe == exe
+        || matches!(
+            (fs::canonicalize(kernel_exe), fs::canonicalize(exe)),
+            (Ok(a), Ok(b)) if a == b
+        )
+}
+
+/// Returns whether we can write to the kernel's files, without changing them.
+fn is_writable(kernel_dir: &Path) -> bool {
+    fs::OpenOptions::new()
+        .append(true)
+        .open(kernel_dir.join("kernel.json"))
+        .is_ok()
+}
+
+fn is_evcxr_kernel(kernel_json: &JsonValue) -> bool {
+    kernel_json["argv"][0]
+        .as_str()
+        .and_then(|exe| Path::new(exe).file_stem())
+        .map(|stem| stem == "evcxr_jupyter")
+        .unwrap_or(false)
+}
+
 // https://jupyter-client.readthedocs.io/en/latest/kernels.html
-fn get_kernel_dir() -> Result<PathBuf> {
-    let jupyter_dir = if let Ok(dir) = env::var("JUPYTER_PATH") {
-        PathBuf::from(dir)
-    } else if let Some(dir) = get_user_kernel_dir() {
-        dir
-    } else {
-        bail!("Couldn't get XDG data directory");
+fn get_kernels_dir(location: &KernelLocation) -> Result<PathBuf> {
+    let jupyter_dir = match location {
+        KernelLocation::User => {
+            if let Ok(dir) = env::var("JUPYTER_PATH") {
+                PathBuf::from(dir)
+            } else if let Some(dir) = get_user_kernel_dir() {
+                dir
+            } else {
+                bail!("Couldn't get XDG data directory");
+            }
+        }
+        KernelLocation::SysPrefix => python_sys_prefix()?.join("share").join("jupyter"),
+        KernelLocation::Prefix(prefix) => prefix.join("share").join("jupyter"),
     };
-    Ok(jupyter_dir.join("kernels").join("rust"))
+    Ok(jupyter_dir.join("kernels"))
+}
+
+/// Returns sys.prefix of whichever Python is first on the path, which is hopefully the one that
+/// Jupyter is installed with.
+fn python_sys_prefix() -> Result<PathBuf> {
+    for python in ["python3", "python"] {
+        if let Ok(output) = std::process::Command::new(python)
+            .args(["-c", "import sys; print(sys.prefix)"])
+            .output()
+        {
+            if output.status.success() {
+                return Ok(PathBuf::from(
+                    String::from_utf8_lossy(&output.stdout).trim_end(),
+                ));
+            }
+        }
+    }
+    bail!("Couldn't determine Python's sys.prefix. Is Python installed?");
 }
 
 #[cfg(not(target_os = "macos"))]
@@ -6,13 +6,12 @@
 // copied, modified, or distributed except according to those terms.
 
 use crate::connection::Connection;
-use crate::connection::HmacSha256;
+use crate::connection::MessageMac;
 use anyhow::anyhow;
 use anyhow::bail;
 use anyhow::Result;
 use bytes::Bytes;
 use chrono::Utc;
-use generic_array::GenericArray;
 use json::JsonValue;
 use json::{self};
 use std::fmt;
@@ -54,21 +53,17 @@ impl RawMessage {
         if let Some(mac_template) = &connection.mac {
             let mut mac = mac_template.clone();
             raw_message.digest(&mut mac);
-            use hmac::Mac;
-            if let Err(error) = mac.verify(GenericArray::from_slice(&hex::decode(&hmac)?)) {
-                bail!("{}", error);
-            }
+            mac.verify(&hex::decode(&hmac)?)?;
         }
 
         Ok(raw_message)
     }
 
     async fn send<S: zeromq::SocketSend>(self, connection: &mut Connection<S>) -> Result<()> {
-        use hmac::Mac;
         let hmac = if let Some(mac_template) = &connection.mac {
             let mut mac = mac_template.clone();
             self.digest(&mut mac);
-            hex::encode(mac.finalize().into_bytes().as_slice())
+            hex::encode(mac.finalize())
         } else {
             String::new()
         };
@@ -88,8 +83,7 @@ impl RawMessage {
         Ok(())
     }
 
-    fn digest(&self, mac: &mut HmacSha256) {
-        use hmac::Mac;
+    fn digest(&self, mac: &mut MessageMac) {
         for part in &self.jparts {
             mac.update(part);
         }
@@ -197,6 +191,11 @@ impl JupyterMessage {
         self
     }
 
+    pub(crate) fn with_metadata(mut self, metadata: JsonValue) -> JupyterMessage {
+        self.metadata = metadata;
+        self
+    }
+
     pub(crate) fn with_message_type(mut self, msg_type: &str) -> JupyterMessage {
         self.header["msg_type"] = JsonValue::String(msg_type.to_owned());
         self
@@ -17,10 +17,82 @@ mod control_file;
 mod core;
 mod install;
 mod jupyter_message;
+mod notebook;
 
-fn run(control_file_name: &str) -> Result<()> {
-    let config = control_file::Control::parse_file(control_file_name)?;
-    core::Server::run(&config)
+fn run(mut args: std::env::Args) -> Result<()> {
+    let control_file_name = args.next().ok_or_else(|| anyhow!("Missing control file"))?;
+    let mut kernel_options = core::KernelOptions::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--toolchain" => kernel_options.toolchain = Some(option_value(&mut args, &arg)?),
+            "--opt" => kernel_options.opt_level = Some(option_value(&mut args, &arg)?),
+            x => bail!("Unrecognised option {}", x),
+        }
+    }
+    let config = control_file::Control::parse_file(&control_file_name)?;
+    core::Server::run(&config, kernel_options)
+}
+
+fn install_options(mut args: std::env::Args) -> Result<install::InstallOptions> {
+    let mut options = install::InstallOptions::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--name" => options.name = option_value(&mut args, &arg)?,
+            "--display-name" => options.display_name = Some(option_value(&mut args, &arg)?),
+            "--toolchain" => options.toolchain = Some(option_value(&mut args, &arg)?),
+            "--opt" => options.opt_level = Some(option_value(&mut args, &arg)?),
+            "--env" => {
+                let value = option_value(&mut args, &arg)?;
+                let (key, value) = value
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("--env should be of the form KEY=VALUE"))?;
+                options.env.push((key.to_owned(), value.to_owned()));
+            }
+            "--sys-prefix" => options.location = install::KernelLocation::SysPrefix,
+            "--prefix" => {
+                options.location =
+                    install::KernelLocation::Prefix(option_value(&mut args, &arg)?.into())
+            }
+            x => bail!("Unrecognised option {}", x),
+        }
+    }
+    Ok(options)
+}
+
+fn option_value(args: &mut std::env::Args, option: &str) -> Result<String> {
+    args.next()
+        .ok_or_else(|| anyhow!("Missing value for {}", option))
+}
+
+fn execute_notebook(mut args: std::env::Args) -> Result<()> {
+    let path = args.next().ok_or_else(|| anyhow!("Missing notebook"))?;
+    let mut options = notebook::ExecuteOptions {
+        allow_errors: false,
+        timeout: None,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--allow-errors" => options.allow_errors = true,
+            "--timeout" => {
+                let seconds = args
+                    .next()
+                    .ok_or_else(|| anyhow!("Missing timeout"))?
+                    .parse::<f64>()
+                    .ok()
+                    // Anything else would make Duration::from_secs_f64 panic.
+                    .filter(|seconds| (0.0..u64::MAX as f64).contains(seconds))
+                    .ok_or_else(|| {
+                        anyhow!("--timeout should be a non-negative number of seconds")
+                    })?;
+                options.timeout = Some(std::time::Duration::from_secs_f64(seconds));
+            }
+            x => bail!("Unrecognised option {}", x),
+        }
+    }
+    if !notebook::execute(std::path::Path::new(&path), &options)? {
+        std::process::exit(1);
+    }
+    Ok(())
 }
 
 fn main() -> Result<()> {
@@ -33,16 +105,28 @@ fn main() -> Result<()> {
                 if let Err(error) = install::update_if_necessary() {
                     eprintln!("Warning: tried to update client, but failed: {}", error);
                 }
-                return run(&args.next().ok_or_else(|| anyhow!("Missing control file"))?);
+                return run(args);
             }
-            "--install" => return install::install(),
-            "--uninstall" => return install::uninstall(),
+            "--execute" => return execute_notebook(args),
+            "--install" => return install::install(&install_options(args)?),
+            "--uninstall" => return install::uninstall(&install_options(args)?),
+            "--list" => return install::list(),
             "--help" => {}
             x => bail!("Unrecognised option {}", x),
         }
     }
     println!("To install, run:\n  {} --install", bin);
-    println!("To uninstall, run:\n  {} --uninstall", bin);
+    println!(
+        "  Options: --name NAME, --display-name NAME, --toolchain TOOLCHAIN, --opt LEVEL,\n  \
+         --env KEY=VALUE (repeatable), --sys-prefix, --prefix DIR"
+    );
+    println!("To uninstall, run:\n  {} --uninstall [--name NAME]", bin);
+    println!("To list installed evcxr kernels, run:\n  {} --list", bin);
+    println!(
+        "To execute a notebook, writing outputs back into it, run:\n  \
+         {} --execute notebook.ipynb [--allow-errors] [--timeout seconds]",
+        bin
+    );
     Ok(())
 }
 
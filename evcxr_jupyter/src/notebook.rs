@@ -0,0 +1,183 @@
+// Copyright 2023 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Headless execution of notebooks, without needing Jupyter to be installed.
+
+use crate::core;
+use anyhow::bail;
+use anyhow::Result;
+use evcxr::CommandContext;
+use json::JsonValue;
+use std::path::Path;
+use std::time::Duration;
+
+pub(crate) struct ExecuteOptions {
+    /// Whether to continue executing subsequent cells after a cell fails.
+    pub(crate) allow_errors: bool,
+    /// How long each cell is allowed to run for before it's interrupted.
+    pub(crate) timeout: Option<Duration>,
+}
+
+/// Executes each code cell of the notebook at `path`, then writes the notebook back with outputs
+/// and execution counts updated. Returns whether all cells that were executed succeeded. Unless
+/// errors are allowed, execution stops at the first failed cell, in which case the remaining cells
+/// are left without outputs.
+pub(crate) fn execute(path: &Path, options: &ExecuteOptions) -> Result<bool> {
+    let mut notebook = json::parse(&std::fs::read_to_string(path)?)?;
+    if notebook["nbformat"].as_u32() != Some(4) {
+        bail!(
+            "Unsupported notebook format {}. Only nbformat 4 is supported",
+            notebook["nbformat"]
+        );
+    }
+    let (mut context, outputs) = CommandContext::new()?;
+    context.execute(":load_config")?;
+    let interrupt_handle = context.interrupt_handle();
+    let mut success = true;
+    let mut execution_count = 0;
+    for cell in notebook["cells"].members_mut() {
+        if cell["cell_type"] != "code" {
+            continue;
+        }
+        cell["outputs"] = JsonValue::new_array();
+        cell["execution_count"] = JsonValue::Null;
+        if !success && !options.allow_errors {
+            continue;
+        }
+        let source = multiline_string(&cell["source"]);
+        execution_count += 1;
+
+        // The timer thread interrupts evaluation if it's not told that we're done before the
+        // timeout elapses. We wait for it to exit before moving on, otherwise if we finished just
+        // as it fired, it could interrupt the next cell.
+        let (done_sender, done_receiver) = crossbeam_channel::bounded::<()>(0);
+        let timer = options.timeout.map(|timeout| {
+            let interrupt_handle = interrupt_handle.clone();
+            std::thread::spawn(move || {
+                if done_receiver.recv_timeout(timeout)
+                    == Err(crossbeam_channel::RecvTimeoutError::Timeout)
+                {
+                    interrupt_handle.interrupt();
+                }
+            })
+        });
+        let start = std::time::Instant::now();
+        let result = context.execute(&source);
+        let duration = start.elapsed();
+        drop(done_sender);
+        if let Some(timer) = timer {
+            let _ = timer.join();
+        }
+
+        let mut cell_outputs = Vec::new();
+        // Output from user code on stdout is all sent before execution completes. Stderr is
+        // forwarded by a separate thread, so we give it a moment to catch up.
+        let stdout: Vec<String> = outputs.stdout.try_iter().collect();
+        let mut stderr = Vec::new();
+        while let Ok(line) = outputs.stderr.recv_timeout(Duration::from_millis(50)) {
+            stderr.push(line);
+        }
+        for (name, lines) in [("stdout", stdout), ("stderr", stderr)] {
+            if !lines.is_empty() {
+                cell_outputs.push(object! {
+                    "output_type" => "stream",
+                    "name" => name,
+                    "text" => split_lines(&(lines.join("\n") + "\n")),
+                });
+            }
+        }
+        match result {
+            Ok(output) => {
+                let metadata = core::timing_metadata(duration, &output.phases);
+                if !output.is_empty() {
+                    cell_outputs.push(object! {
+                        "output_type" => "execute_result",
+                        "execution_count" => execution_count,
+                        "data" => core::output_data(output.content_by_mime_type),
+                        "metadata" => metadata.clone(),
+                    });
+                }
+                for warning in &output.warnings {
+                    cell_outputs.push(object! {
+                        "output_type" => "display_data",
+                        "data" => core::warning_data(warning, &source, execution_count),
+                        "metadata" => object!(),
+                    });
+                }
+                if let Some(duration) = output.timing {
+                    cell_outputs.push(object! {
+                        "output_type" => "display_data",
+                        "data" => core::timing_data(duration),
+                        "metadata" => object!(),
+                    });
+                }
+                if let Some(memory) = &output.memory {
+                    cell_outputs.push(object! {
+                        "output_type" => "display_data",
+                        "data" => core::memory_data(memory),
+                        "metadata" => object!(),
+                    });
+                }
+                cell["metadata"]["evcxr"] = metadata["evcxr"].clone();
+            }
+            Err(evcxr::Error::Cancelled) if options.timeout.is_some() => {
+                let message = format!(
+                    "Cell execution timed out after {}s",
+                    options.timeout.unwrap_or_default().as_secs_f64()
+                );
+                cell_outputs.push(object! {
+                    "output_type" => "error",
+                    "ename" => "Timeout",
+                    "evalue" => message.clone(),
+                    "traceback" => array![message],
+                });
+                success = false;
+            }
+            Err(error) => {
+                for (output_type, mut content) in
+                    core::error_messages(&error, &source, execution_count)
+                {
+                    content.remove("transient");
+                    content["output_type"] = output_type.into();
+                    cell_outputs.push(content);
+                }
+                success = false;
+            }
+        }
+        cell["outputs"] = cell_outputs.into();
+        cell["execution_count"] = execution_count.into();
+    }
+    drop(context);
+    std::fs::write(path, json::stringify_pretty(notebook, 1) + "\n")?;
+    Ok(success)
+}
+
+/// nbformat allows multiline strings to be stored either as a single string or as a list of lines.
+fn multiline_string(value: &JsonValue) -> String {
+    if value.is_array() {
+        value.members().filter_map(JsonValue::as_str).collect()
+    } else {
+        value.as_str().unwrap_or_default().to_owned()
+    }
+}
+
+/// Splits text into lines, keeping line endings, which is how Jupyter stores multiline strings.
+fn split_lines(text: &str) -> Vec<&str> {
+    text.split_inclusive('\n').collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multiline_strings() {
+        assert_eq!(multiline_string(&"a\nb".into()), "a\nb");
+        assert_eq!(multiline_string(&array!["a\n", "b"]), "a\nb");
+        assert_eq!(split_lines("a\nb\n"), vec!["a\n", "b\n"]);
+    }
+}
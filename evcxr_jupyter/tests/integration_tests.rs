@@ -8,14 +8,8 @@
 
 use std::process;
 
-// Checks that our binary can be executed. This used to be an important thing to
-// check due to https://github.com/rust-lang/rust/issues/45601 which meant that
-// we could easily end up with a binary that couldn't be executed (without
-// LD_LIBRARY PATH or similar). That bug is now long fixed, but this test
-// perhaps still has some value.
-#[test]
-fn test_binary_execution() {
-    let output = process::Command::new(
+fn evcxr_jupyter_command() -> process::Command {
+    process::Command::new(
         std::env::current_exe()
             .unwrap()
             .parent()
@@ -24,14 +18,186 @@ fn test_binary_execution() {
             .unwrap()
             .join("evcxr_jupyter"),
     )
-    .arg("--help")
-    .env_remove("LD_LIBRARY_PATH")
-    .output()
-    .unwrap();
+}
+
+// Checks that our binary can be executed. This used to be an important thing to
+// check due to https://github.com/rust-lang/rust/issues/45601 which meant that
+// we could easily end up with a binary that couldn't be executed (without
+// LD_LIBRARY PATH or similar). That bug is now long fixed, but this test
+// perhaps still has some value.
+#[test]
+fn test_binary_execution() {
+    let output = evcxr_jupyter_command()
+        .arg("--help")
+        .env_remove("LD_LIBRARY_PATH")
+        .output()
+        .unwrap();
     let stdout = std::str::from_utf8(&output.stdout).unwrap();
     let stderr = std::str::from_utf8(&output.stderr).unwrap();
     assert_eq!(stderr, "");
     if !stdout.contains("To install, run") {
         panic!("Unexpected output:\n{:?}", stdout);
     }
+}
+
+#[test]
+fn test_execute_notebook() {
+    let path = std::env::temp_dir().join(format!("evcxr_test_{}.ipynb", process::id()));
+    let notebook = json::object! {
+        "cells" => json::array![
+            json::object! {
+                "cell_type" => "code",
+                "execution_count" => json::Null,
+                "metadata" => json::object!(),
+                "outputs" => json::array![],
+                "source" => json::array!["let a = 40;\n", "println!(\"a={a}\");\n", "a + 2"],
+            },
+            json::object! {
+                "cell_type" => "code",
+                "execution_count" => json::Null,
+                "metadata" => json::object!(),
+                "outputs" => json::array![],
+                "source" => "let b: String = a;",
+            },
+            json::object! {
+                "cell_type" => "code",
+                "execution_count" => json::Null,
+                "metadata" => json::object!(),
+                "outputs" => json::array![],
+                "source" => "a",
+            },
+        ],
+        "metadata" => json::object!(),
+        "nbformat" => 4,
+        "nbformat_minor" => 5,
+    };
+    std::fs::write(&path, notebook.dump()).unwrap();
+    let status = evcxr_jupyter_command()
+        .arg("--execute")
+        .arg(&path)
+        .status()
+        .unwrap();
+    let executed = json::parse(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(status.code(), Some(1));
+    let cells = &executed["cells"];
+    assert_eq!(cells[0]["execution_count"], 1);
+    assert_eq!(cells[0]["outputs"][0]["text"], json::array!["a=40\n"]);
+    assert_eq!(cells[0]["outputs"][1]["data"]["text/plain"], "42");
+    // Timings are recorded in metadata rather than being displayed.
+    assert_eq!(cells[0]["outputs"].len(), 2);
+    let timing = &cells[0]["metadata"]["evcxr"]["timing"];
+    assert!(timing["duration_ms"].as_f64().unwrap() > 0.0);
+    assert!(timing["phases"]
+        .members()
+        .any(|phase| phase["name"] == "Execution"));
+    assert_eq!(
+        cells[0]["outputs"][1]["metadata"]["evcxr"]["timing"],
+        *timing
+    );
+    assert_eq!(cells[1]["execution_count"], 2);
+    // Compilation errors are rendered as HTML, followed by the error itself.
+    assert_eq!(cells[1]["outputs"][0]["output_type"], "display_data");
+    assert!(cells[1]["outputs"][0]["data"]["text/html"]
+        .as_str()
+        .unwrap()
+        .contains("E0308"));
+    assert_eq!(cells[1]["outputs"][1]["output_type"], "error");
+    // Execution stops at the first error.
+    assert!(cells[2]["execution_count"].is_null());
+    assert!(cells[2]["outputs"].is_empty());
+}
+
+#[test]
+fn test_execute_notebook_warnings() {
+    let path = std::env::temp_dir().join(format!("evcxr_test_warnings_{}.ipynb", process::id()));
+    let notebook = json::object! {
+        "cells" => json::array![
+            json::object! {
+                "cell_type" => "code",
+                "execution_count" => json::Null,
+                "metadata" => json::object!(),
+                "outputs" => json::array![],
+                "source" => "fn f() -> Result<(), ()> { Ok(()) }\nf();",
+            },
+            json::object! {
+                "cell_type" => "code",
+                "execution_count" => json::Null,
+                "metadata" => json::object!(),
+                "outputs" => json::array![],
+                "source" => "42",
+            },
+        ],
+        "metadata" => json::object!(),
+        "nbformat" => 4,
+        "nbformat_minor" => 5,
+    };
+    std::fs::write(&path, notebook.dump()).unwrap();
+    let status = evcxr_jupyter_command()
+        .arg("--execute")
+        .arg(&path)
+        .status()
+        .unwrap();
+    let executed = json::parse(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(status.success());
+    let cells = &executed["cells"];
+    // Warnings are displayed, but don't stop execution.
+    assert_eq!(cells[0]["outputs"][0]["output_type"], "display_data");
+    assert!(cells[0]["outputs"][0]["data"]["text/html"]
+        .as_str()
+        .unwrap()
+        .contains("unused_must_use"));
+    assert_eq!(cells[1]["execution_count"], 2);
+    assert_eq!(cells[1]["outputs"][0]["data"]["text/plain"], "42");
+}
+
+#[test]
+fn test_execute_invalid_timeout() {
+    for timeout in ["-1", "NaN", "inf", "soon"] {
+        let output = evcxr_jupyter_command()
+            .args(["--execute", "notebook.ipynb", "--timeout", timeout])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = std::str::from_utf8(&output.stderr).unwrap();
+        assert!(stderr.contains("--timeout should be"), "{}", stderr);
+    }
+}
+
+#[test]
+fn test_install_list_and_uninstall() {
+    let jupyter_path = std::env::temp_dir().join(format!("evcxr_test_jupyter_{}", process::id()));
+    let run = |args: &[&str]| {
+        let output = evcxr_jupyter_command()
+            .args(args)
+            .env("JUPYTER_PATH", &jupyter_path)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        String::from_utf8(output.stdout).unwrap()
+    };
+    run(&[
+        "--install",
+        "--name",
+        "rust-nightly",
+        "--toolchain",
+        "nightly",
+        "--opt",
+        "0",
+        "--env",
+        "A=b=c",
+    ]);
+    let kernel_json =
+        std::fs::read_to_string(jupyter_path.join("kernels/rust-nightly/kernel.json")).unwrap();
+    let kernel_json = json::parse(&kernel_json).unwrap();
+    assert_eq!(kernel_json["display_name"], "Rust (nightly)");
+    assert_eq!(kernel_json["argv"][3], "--toolchain");
+    assert_eq!(kernel_json["argv"][4], "nightly");
+    assert_eq!(kernel_json["argv"][6], "0");
+    assert_eq!(kernel_json["env"]["A"], "b=c");
+    assert!(run(&["--list"]).contains("rust-nightly: Rust (nightly)"));
+    run(&["--uninstall", "--name", "rust-nightly"]);
+    assert!(!run(&["--list"]).contains("rust-nightly"));
+    std::fs::remove_dir_all(&jupyter_path).unwrap();
 }
\ No newline at end of file
@@ -12,9 +12,11 @@ use colored::*;
 use evcxr::CommandContext;
 use evcxr::CompilationError;
 use evcxr::Error;
+use evcxr::EvalOutputs;
 use evcxr::Theme;
 use evcxr_repl::BgInitMutex;
 use evcxr_repl::EvcxrRustylineHelper;
+use evcxr_repl::MarkdownDocument;
 use rustyline::error::ReadlineError;
 use rustyline::At;
 use rustyline::Cmd;
@@ -28,6 +30,8 @@ use rustyline::Movement;
 use rustyline::Word;
 use std::fs;
 use std::io;
+use std::path::Path;
+use std::path::PathBuf;
 use std::sync::Arc;
 use structopt::StructOpt;
 
@@ -96,6 +100,7 @@ impl Repl {
                 if let Some(text) = output.get("text/plain") {
                     println!("{text}");
                 }
+                Repl::display_errors(to_run, output.warnings);
                 if let Some(duration) = output.timing {
                     println!("{}", format!("Took {}ms", duration.as_millis()).blue());
 
@@ -106,10 +111,13 @@ impl Repl {
                         );
                     }
                 }
+                if let Some(memory) = output.memory {
+                    println!("{}", memory.to_string().blue());
+                }
                 true
             }
             Err(evcxr::Error::CompilationErrors(errors)) => {
-                self.display_errors(to_run, errors);
+                Repl::display_errors(to_run, errors);
                 false
             }
             Err(err) => {
@@ -125,7 +133,7 @@ impl Repl {
         Ok(())
     }
 
-    fn display_errors(&mut self, source: &str, errors: Vec<CompilationError>) {
+    fn display_errors(source: &str, errors: Vec<CompilationError>) {
         use yansi::Paint;
         if cfg!(windows) && !Paint::enable_windows_ascii() {
             Paint::disable()
@@ -250,6 +258,33 @@ struct Options {
         default_value = "emacs"
      )]
     edit_mode: rustyline::EditMode,
+    /// Replays a transcript of a session, with inputs prefixed by `>> `, reporting any outputs that
+    /// differ from those in the transcript, then exits.
+    #[structopt(long, parse(from_os_str))]
+    transcript: Option<PathBuf>,
+    #[structopt(subcommand)]
+    command: Option<Subcommand>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Subcommand {
+    /// Runs a script non-interactively, stopping at the first error. Cells are separated by blank
+    /// lines, or if present, by lines starting with `// %%`.
+    Run {
+        /// The script to run, or `-` to read from stdin.
+        #[structopt(parse(from_os_str))]
+        script: PathBuf,
+    },
+    /// Runs the ```rust code blocks in a Markdown file, writing the outputs of each into an
+    /// ```output block following it. Blocks annotated with `ignore` (e.g. ```rust,ignore) are
+    /// skipped and those annotated with `should_panic` must panic.
+    Markdown {
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+        /// Don't update the file, instead fail if any outputs differ from what's in the file.
+        #[structopt(long)]
+        check: bool,
+    },
 }
 
 fn main() -> Result<()> {
@@ -260,6 +295,23 @@ fn main() -> Result<()> {
     #[cfg(windows)]
     colored::control::set_virtual_terminal(true).ok();
 
+    if let Some(transcript) = &options.transcript {
+        if !check_transcript(transcript, &options.opt)? {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(command) = &options.command {
+        let success = match command {
+            Subcommand::Run { script } => run_script(script, &options.opt)?,
+            Subcommand::Markdown { file, check } => run_markdown(file, &options.opt, *check)?,
+        };
+        if !success {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     println!("Welcome to evcxr. For help, type :help");
     // Print this now, because we silence `:load_config` (writing to stdout
     // interfers with rustyline somewhat).
@@ -343,14 +395,175 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+/// A `CommandContext` set up for running code non-interactively.
+struct ScriptContext {
+    command_context: CommandContext,
+    stdout: crossbeam_channel::Receiver<String>,
+    stderr_thread: std::thread::JoinHandle<()>,
+}
+
+impl ScriptContext {
+    fn new(opt: &str) -> Result<ScriptContext> {
+        let (mut command_context, outputs) = CommandContext::new()?;
+        let stderr = outputs.stderr;
+        let stderr_thread = std::thread::spawn(move || {
+            for line in stderr {
+                eprintln!("{line}");
+            }
+        });
+        command_context.execute(":load_config --quiet")?;
+        if !opt.is_empty() {
+            command_context.set_opt_level(opt)?;
+        }
+        setup_ctrlc_handler(&command_context);
+        Ok(ScriptContext {
+            command_context,
+            stdout: outputs.stdout,
+            stderr_thread,
+        })
+    }
+
+    /// Executes `code`, returning the result together with any lines that it wrote to stdout.
+    fn execute(&mut self, code: &str) -> (Result<EvalOutputs, Error>, Vec<String>) {
+        let result = self.command_context.execute(code);
+        // Output from user code is all sent before execution completes, so we've got all of it.
+        (result, self.stdout.try_iter().collect())
+    }
+
+    fn finish(self) {
+        // Dropping the context shuts down the subprocess, which closes stderr, allowing us to wait
+        // until everything written to it has been printed.
+        drop(self.command_context);
+        let _ = self.stderr_thread.join();
+    }
+}
+
+fn read_input(path: &Path) -> Result<String> {
+    Ok(if path == Path::new("-") {
+        io::read_to_string(io::stdin())?
+    } else {
+        fs::read_to_string(path)?
+    })
+}
+
+/// Runs the supplied script, returning whether all cells ran successfully.
+fn run_script(script: &Path, opt: &str) -> Result<bool> {
+    let source = read_input(script)?;
+    let mut context = ScriptContext::new(opt)?;
+    let mut success = true;
+    for cell in evcxr_repl::split_into_cells(&source) {
+        let (result, stdout) = context.execute(&cell);
+        for line in stdout {
+            println!("{line}");
+        }
+        match result {
+            Ok(output) => {
+                if let Some(text) = output.get("text/plain") {
+                    println!("{text}");
+                }
+                Repl::display_errors(&cell, output.warnings);
+                // The panic message will already have been written to stderr.
+                success = !output.panicked;
+            }
+            Err(Error::CompilationErrors(errors)) => {
+                Repl::display_errors(&cell, errors);
+                success = false;
+            }
+            Err(err) => {
+                eprintln!("{err}");
+                success = false;
+            }
+        }
+        if !success {
+            break;
+        }
+    }
+    context.finish();
+    Ok(success)
+}
+
+/// Runs the Rust code blocks in a Markdown file, then writes their outputs back into the file, or
+/// with `check`, reports any outputs that differ. Returns whether everything succeeded.
+fn run_markdown(path: &Path, opt: &str, check: bool) -> Result<bool> {
+    let source = fs::read_to_string(path)?;
+    let mut document = MarkdownDocument::parse(&source);
+    let mut context = ScriptContext::new(opt)?;
+    let mut success = true;
+    for snippet in document.snippets_mut().filter(|snippet| !snippet.ignore) {
+        let location = format!("{}:{}", path.display(), snippet.line);
+        let (result, stdout) = context.execute(&snippet.code);
+        let mut output = String::new();
+        for line in stdout {
+            output.push_str(&line);
+            output.push('\n');
+        }
+        match result {
+            Ok(outputs) => {
+                if let Some(text) = outputs.get("text/plain") {
+                    output.push_str(text);
+                }
+                if outputs.panicked != snippet.should_panic {
+                    let expectation = if snippet.should_panic {
+                        "Expected a panic, but code completed normally"
+                    } else {
+                        "Unexpected panic"
+                    };
+                    eprintln!("{location}: {expectation}");
+                    success = false;
+                }
+            }
+            Err(Error::CompilationErrors(errors)) => {
+                eprintln!("{location}: Compilation failed");
+                Repl::display_errors(&snippet.code, errors);
+                success = false;
+            }
+            Err(err) => {
+                eprintln!("{location}: {err}");
+                success = false;
+            }
+        }
+        if !success {
+            break;
+        }
+        if check {
+            let expected = snippet.output().unwrap_or("");
+            if expected.trim_end() != output.trim_end() {
+                eprintln!("{location}: Output differs\nExpected:\n{expected}\nActual:\n{output}");
+                success = false;
+            }
+        } else {
+            snippet.set_output(&output);
+        }
+    }
+    context.finish();
+    if success && !check {
+        let rendered = document.render();
+        if rendered != source {
+            fs::write(path, rendered)?;
+        }
+    }
+    Ok(success)
+}
+
+/// Replays the supplied transcript, returning whether all outputs matched.
+fn check_transcript(path: &Path, opt: &str) -> Result<bool> {
+    let transcript = read_input(path)?;
+    let mut context = ScriptContext::new(opt)?;
+    let result = evcxr::run_transcript(&mut context.command_context, &context.stdout, &transcript);
+    context.finish();
+    if let Err(failure) = result {
+        eprint!("{failure}");
+        return Ok(false);
+    }
+    Ok(true)
+}
+
 fn setup_ctrlc_handler(command_context: &CommandContext) {
-    let subprocess = command_context.process_handle();
+    let interrupt_handle = command_context.interrupt_handle();
     // If we can't register a ctrl-c handler for some reason, then we just don't
     // support catching ctrl-c. The user probably wouldn't want to see an error
     // printed every time, so we ignore it.
-    let _ = ctrlc::set_handler(move || {
-        let _ = subprocess.lock().unwrap().kill();
-    });
+    let _ = ctrlc::set_handler(move || interrupt_handle.interrupt());
 }
 
 fn parse_edit_mode(src: &str) -> Result<EditMode, &str> {
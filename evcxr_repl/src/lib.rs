@@ -6,9 +6,14 @@
 // copied, modified, or distributed except according to those terms.
 
 mod bginit;
+mod markdown;
 mod repl;
 mod scan;
+mod script;
 
 pub use bginit::BgInitMutex;
 pub use bginit::BgInitMutexGuard;
+pub use markdown::MarkdownDocument;
+pub use markdown::Snippet;
 pub use repl::EvcxrRustylineHelper;
+pub use script::split_into_cells;
@@ -0,0 +1,261 @@
+// Copyright 2023 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Support for executing the Rust code blocks in a Markdown document and writing their outputs
+//! back into the document. The outputs of each code block are written in a fenced block with the
+//! info string `output` immediately following the code block. Any such block already present is
+//! replaced.
+
+const OUTPUT_INFO: &str = "output";
+
+/// A Markdown document, split into Rust code blocks (snippets) and everything else.
+pub struct MarkdownDocument {
+    pieces: Vec<Piece>,
+}
+
+enum Piece {
+    Text(String),
+    Snippet(Snippet),
+}
+
+/// A fenced code block with the info string `rust`, optionally followed by annotations, separated
+/// by commas or spaces. e.g. "```rust,should_panic".
+pub struct Snippet {
+    /// The code inside the block.
+    pub code: String,
+    /// The 1-based line number of the opening fence.
+    pub line: usize,
+    /// Whether the block was annotated with `ignore`, meaning that it shouldn't be run.
+    pub ignore: bool,
+    /// Whether the block was annotated with `should_panic`.
+    pub should_panic: bool,
+    /// The text of the code block, including fences.
+    raw: String,
+    /// The contents of the output block that followed the code block, if any.
+    output: Option<String>,
+    /// The text of the output block, including fences and any blank lines that preceded it.
+    raw_output: Option<String>,
+}
+
+impl MarkdownDocument {
+    pub fn parse(source: &str) -> MarkdownDocument {
+        let lines: Vec<&str> = source.split_inclusive('\n').collect();
+        let mut pieces = Vec::new();
+        let mut text = String::new();
+        let mut index = 0;
+        while index < lines.len() {
+            let Some(block) = parse_fenced_block(&lines, index) else {
+                text.push_str(lines[index]);
+                index += 1;
+                continue;
+            };
+            let Some(annotations) = rust_annotations(&block.info) else {
+                text.push_str(&block.raw);
+                index = block.end;
+                continue;
+            };
+            if !text.is_empty() {
+                pieces.push(Piece::Text(std::mem::take(&mut text)));
+            }
+            let mut snippet = Snippet {
+                code: block.content,
+                line: index + 1,
+                ignore: annotations.contains(&"ignore"),
+                should_panic: annotations.contains(&"should_panic"),
+                raw: block.raw,
+                output: None,
+                raw_output: None,
+            };
+            index = block.end;
+            let mut output_start = index;
+            while output_start < lines.len() && lines[output_start].trim().is_empty() {
+                output_start += 1;
+            }
+            if let Some(output_block) = parse_fenced_block(&lines, output_start) {
+                if output_block.info == OUTPUT_INFO {
+                    snippet.output = Some(output_block.content);
+                    snippet.raw_output = Some(lines[index..output_block.end].concat());
+                    index = output_block.end;
+                }
+            }
+            pieces.push(Piece::Snippet(snippet));
+        }
+        if !text.is_empty() {
+            pieces.push(Piece::Text(text));
+        }
+        MarkdownDocument { pieces }
+    }
+
+    pub fn snippets(&self) -> impl Iterator<Item = &Snippet> {
+        self.pieces.iter().filter_map(|piece| match piece {
+            Piece::Snippet(snippet) => Some(snippet),
+            Piece::Text(_) => None,
+        })
+    }
+
+    pub fn snippets_mut(&mut self) -> impl Iterator<Item = &mut Snippet> {
+        self.pieces.iter_mut().filter_map(|piece| match piece {
+            Piece::Snippet(snippet) => Some(snippet),
+            Piece::Text(_) => None,
+        })
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Text(text) => out.push_str(text),
+                Piece::Snippet(snippet) => {
+                    out.push_str(&snippet.raw);
+                    if let Some(raw_output) = &snippet.raw_output {
+                        if !out.ends_with('\n') {
+                            out.push('\n');
+                        }
+                        out.push_str(raw_output);
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+impl Snippet {
+    /// Returns the contents of the output block that follows this snippet, if any.
+    pub fn output(&self) -> Option<&str> {
+        self.output.as_deref()
+    }
+
+    /// Sets the contents of the output block that follows this snippet. If `output` is empty, then
+    /// any output block is removed.
+    pub fn set_output(&mut self, output: &str) {
+        let output = if output.is_empty() || output.ends_with('\n') {
+            output.to_owned()
+        } else {
+            format!("{output}\n")
+        };
+        if self.output.as_deref().unwrap_or("") == output {
+            return;
+        }
+        if output.is_empty() {
+            self.output = None;
+            self.raw_output = None;
+        } else {
+            let fence = "`".repeat(longest_backtick_run(&output).max(2) + 1);
+            self.raw_output = Some(format!("\n{fence}{OUTPUT_INFO}\n{output}{fence}\n"));
+            self.output = Some(output);
+        }
+    }
+}
+
+struct FencedBlock {
+    info: String,
+    content: String,
+    raw: String,
+    /// Index of the line after the closing fence.
+    end: usize,
+}
+
+/// Parses a fenced code block starting at `lines[start]`. Blocks that are indented or aren't
+/// closed are ignored.
+fn parse_fenced_block(lines: &[&str], start: usize) -> Option<FencedBlock> {
+    let opening = lines.get(start)?.trim_end();
+    let fence_char = opening.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let fence_len = opening.chars().take_while(|c| *c == fence_char).count();
+    if fence_len < 3 {
+        return None;
+    }
+    let info = opening[fence_len..].trim();
+    if fence_char == '`' && info.contains('`') {
+        return None;
+    }
+    for (end, line) in lines.iter().enumerate().skip(start + 1) {
+        let line = line.trim_end();
+        if line.len() >= fence_len && line.chars().all(|c| c == fence_char) {
+            return Some(FencedBlock {
+                info: info.to_owned(),
+                content: lines[start + 1..end].concat(),
+                raw: lines[start..=end].concat(),
+                end: end + 1,
+            });
+        }
+    }
+    None
+}
+
+/// If `info` is that of a Rust code block, returns any annotations that follow `rust`.
+fn rust_annotations(info: &str) -> Option<Vec<&str>> {
+    let mut words = info.split(|c: char| c == ',' || c.is_whitespace());
+    if words.next() != Some("rust") {
+        return None;
+    }
+    Some(words.filter(|word| !word.is_empty()).collect())
+}
+
+fn longest_backtick_run(text: &str) -> usize {
+    text.split(|c| c != '`').map(str::len).max().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_render_unchanged() {
+        let source = "# Title\n\n```rust\nlet a = 1;\n```\n\n```output\nstale\n```\n\nText\n\
+            ```python\nprint(1)\n```\n````rust,ignore\n```\n````";
+        let document = MarkdownDocument::parse(source);
+        assert_eq!(document.render(), source);
+        let snippets: Vec<_> = document.snippets().collect();
+        assert_eq!(snippets.len(), 2);
+        assert_eq!(snippets[0].code, "let a = 1;\n");
+        assert_eq!(snippets[0].line, 3);
+        assert_eq!(snippets[0].output(), Some("stale\n"));
+        assert!(!snippets[0].ignore);
+        assert_eq!(snippets[1].code, "```\n");
+        assert!(snippets[1].ignore);
+    }
+
+    #[test]
+    fn annotations() {
+        let document = MarkdownDocument::parse("```rust should_panic\npanic!()\n```\n");
+        let snippet = document.snippets().next().unwrap();
+        assert!(snippet.should_panic);
+        assert!(!snippet.ignore);
+    }
+
+    #[test]
+    fn insert_replace_and_remove_output() {
+        let mut document = MarkdownDocument::parse("```rust\n1 + 1\n```\nText\n");
+        document.snippets_mut().next().unwrap().set_output("2");
+        assert_eq!(
+            document.render(),
+            "```rust\n1 + 1\n```\n\n```output\n2\n```\nText\n"
+        );
+
+        let mut document = MarkdownDocument::parse(&document.render());
+        document.snippets_mut().next().unwrap().set_output("3\n");
+        assert_eq!(
+            document.render(),
+            "```rust\n1 + 1\n```\n\n```output\n3\n```\nText\n"
+        );
+
+        let mut document = MarkdownDocument::parse(&document.render());
+        document.snippets_mut().next().unwrap().set_output("");
+        assert_eq!(document.render(), "```rust\n1 + 1\n```\nText\n");
+    }
+
+    #[test]
+    fn output_containing_fence() {
+        let mut document = MarkdownDocument::parse("```rust\nx\n```\n");
+        document.snippets_mut().next().unwrap().set_output("```\n");
+        let rendered = document.render();
+        assert_eq!(rendered, "```rust\nx\n```\n\n````output\n```\n````\n");
+        let document = MarkdownDocument::parse(&rendered);
+        assert_eq!(document.snippets().next().unwrap().output(), Some("```\n"));
+    }
+}
@@ -0,0 +1,77 @@
+// Copyright 2023 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use crate::scan::validate_source_fragment;
+use crate::scan::FragmentValidity;
+
+/// A line starting with this separates cells. Anything following it on the same line is ignored.
+const CELL_MARKER: &str = "// %%";
+
+/// Splits a script into cells to be executed one after the other. If the script contains any
+/// marker lines (see `CELL_MARKER`), then only those separate cells. Otherwise cells are separated
+/// by blank lines, except where the code so far is obviously incomplete, such as a function
+/// containing a blank line. Cells containing only whitespace are dropped.
+pub fn split_into_cells(source: &str) -> Vec<String> {
+    let has_markers = source.lines().any(is_marker);
+    let mut cells = Vec::new();
+    let mut current = String::new();
+    for line in source.lines() {
+        let is_boundary = if has_markers {
+            is_marker(line)
+        } else {
+            line.trim().is_empty()
+                && validate_source_fragment(&current) != FragmentValidity::Incomplete
+        };
+        if is_boundary {
+            push_cell(&mut cells, std::mem::take(&mut current));
+        } else {
+            current.push_str(line);
+            current.push('\n');
+        }
+    }
+    push_cell(&mut cells, current);
+    cells
+}
+
+fn is_marker(line: &str) -> bool {
+    line.trim_start().starts_with(CELL_MARKER)
+}
+
+fn push_cell(cells: &mut Vec<String>, cell: String) {
+    if !cell.trim().is_empty() {
+        cells.push(cell);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blank_line_separation() {
+        assert_eq!(
+            split_into_cells("let a = 1;\n\n\na + 1\n"),
+            vec!["let a = 1;\n", "a + 1\n"]
+        );
+    }
+
+    #[test]
+    fn blank_line_in_incomplete_code() {
+        assert_eq!(
+            split_into_cells("fn foo() {\n    1\n\n}\n\nfoo()"),
+            vec!["fn foo() {\n    1\n\n}\n", "foo()\n"]
+        );
+    }
+
+    #[test]
+    fn marker_separation() {
+        assert_eq!(
+            split_into_cells("// %% First\nlet a = 1;\n\nlet b = 2;\n// %%\na + b\n"),
+            vec!["let a = 1;\n\nlet b = 2;\n", "a + b\n"]
+        );
+    }
+}
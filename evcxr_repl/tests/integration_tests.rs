@@ -5,16 +5,11 @@
 // or https://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use std::io::Write;
 use std::process;
 
-// Checks that our binary can be executed. This used to be an important thing to
-// check due to https://github.com/rust-lang/rust/issues/45601 which meant that
-// we could easily end up with a binary that couldn't be executed (without
-// LD_LIBRARY PATH or similar). That bug is now long fixed, but this test
-// perhaps still has some value.
-#[test]
-fn test_binary_execution() {
-    let output = process::Command::new(
+fn evcxr_command() -> process::Command {
+    process::Command::new(
         std::env::current_exe()
             .unwrap()
             .parent()
@@ -23,9 +18,19 @@ fn test_binary_execution() {
             .unwrap()
             .join("evcxr"),
     )
-    .env_remove("LD_LIBRARY_PATH")
-    .output()
-    .unwrap();
+}
+
+// Checks that our binary can be executed. This used to be an important thing to
+// check due to https://github.com/rust-lang/rust/issues/45601 which meant that
+// we could easily end up with a binary that couldn't be executed (without
+// LD_LIBRARY PATH or similar). That bug is now long fixed, but this test
+// perhaps still has some value.
+#[test]
+fn test_binary_execution() {
+    let output = evcxr_command()
+        .env_remove("LD_LIBRARY_PATH")
+        .output()
+        .unwrap();
     let stdout = std::str::from_utf8(&output.stdout).unwrap();
     let stderr = std::str::from_utf8(&output.stderr).unwrap();
     assert_eq!(stderr, "");
@@ -33,3 +38,28 @@ fn test_binary_execution() {
         panic!("Unexpected output:\n{:?}", stdout);
     }
 }
+
+#[test]
+fn test_run_script_stops_at_first_error() {
+    let mut child = evcxr_command()
+        .arg("run")
+        .arg("-")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"let a = 40;\nprintln!(\"a={a}\");\n\na + 2\n\nlet b: String = a;\n\n999\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let stdout = std::str::from_utf8(&output.stdout).unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stdout.starts_with("a=40\n42\n"),
+        "Unexpected output:\n{stdout}"
+    );
+    assert!(!stdout.contains("999"), "Unexpected output:\n{stdout}");
+}
@@ -0,0 +1,318 @@
+// Copyright 2023 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use std::collections::BTreeMap;
+use std::panic;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+// The following need to match the values in evcxr.
+const COMM_PREFIX: &str = "EVCXR_COMM ";
+
+type Handler<T> = Arc<Mutex<Box<T>>>;
+type TargetHandler = dyn FnMut(Comm, &str) + Send;
+type MessageHandler = dyn FnMut(&str) + Send;
+
+/// A Jupyter comm - a channel for exchanging messages with the frontend. Messages are JSON values,
+/// which are passed to and from user code as JSON text. Messages sent to user code are only
+/// received while the kernel isn't executing anything else.
+#[derive(Clone, Debug)]
+pub struct Comm {
+    id: String,
+}
+
+impl Comm {
+    /// Opens a comm with the specified target in the frontend, sending `data`, which should be
+    /// JSON, with the open message.
+    pub fn open(target_name: &str, data: &str) -> Comm {
+        Comm::open_with_metadata(target_name, data, "{}")
+    }
+
+    /// Like `open`, but also sends `metadata`, which should be a JSON object, with the open
+    /// message.
+    pub fn open_with_metadata(target_name: &str, data: &str, metadata: &str) -> Comm {
+        let comm = Comm { id: new_comm_id() };
+        send_to_kernel(&[
+            ("type", json_string("open")),
+            ("comm_id", json_string(&comm.id)),
+            ("target_name", json_string(target_name)),
+            ("data", single_line(data)),
+            ("metadata", single_line(metadata)),
+        ]);
+        comm
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Sends a message to the frontend. `data` should be JSON.
+    pub fn send(&self, data: &str) {
+        send_to_kernel(&[
+            ("type", json_string("msg")),
+            ("comm_id", json_string(&self.id)),
+            ("data", single_line(data)),
+        ]);
+    }
+
+    /// Closes the comm, sending `data`, which should be JSON, with the close message. Any
+    /// registered handlers are discarded.
+    pub fn close(&self, data: &str) {
+        lock(&REGISTRY).comms.remove(&self.id);
+        send_to_kernel(&[
+            ("type", json_string("close")),
+            ("comm_id", json_string(&self.id)),
+            ("data", single_line(data)),
+        ]);
+    }
+
+    /// Registers a handler to be called with the data of each message received from the frontend.
+    pub fn on_msg<F: FnMut(&str) + Send + 'static>(&self, handler: F) {
+        let handler: Handler<MessageHandler> = Arc::new(Mutex::new(Box::new(handler)));
+        lock(&REGISTRY)
+            .comms
+            .entry(self.id.clone())
+            .or_default()
+            .on_msg
+            .push(handler);
+    }
+
+    /// Registers a handler to be called with the data of the close message if the frontend closes
+    /// the comm.
+    pub fn on_close<F: FnMut(&str) + Send + 'static>(&self, handler: F) {
+        let handler: Handler<MessageHandler> = Arc::new(Mutex::new(Box::new(handler)));
+        lock(&REGISTRY)
+            .comms
+            .entry(self.id.clone())
+            .or_default()
+            .on_close
+            .push(handler);
+    }
+}
+
+/// Registers a handler to be called when the frontend opens a comm with the specified target. The
+/// handler receives the new comm and the data sent with the open message. If the frontend opens a
+/// comm with a target for which there's no handler, the comm is closed.
+pub fn register_comm_target<F: FnMut(Comm, &str) + Send + 'static>(target_name: &str, handler: F) {
+    let handler: Handler<TargetHandler> = Arc::new(Mutex::new(Box::new(handler)));
+    lock(&REGISTRY)
+        .targets
+        .insert(target_name.to_owned(), handler);
+}
+
+/// Each cell is compiled to a separate shared object containing its own copy of this crate, so each
+/// has its own registry. Handlers end up in the registry of the cell that registered them. evcxr
+/// passes messages from the frontend to every cell via `evcxr_runtime_comm_dispatch_v1`.
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    targets: BTreeMap::new(),
+    comms: BTreeMap::new(),
+});
+
+struct Registry {
+    targets: BTreeMap<String, Handler<TargetHandler>>,
+    comms: BTreeMap<String, CommHandlers>,
+}
+
+#[derive(Default)]
+struct CommHandlers {
+    on_msg: Vec<Handler<MessageHandler>>,
+    on_close: Vec<Handler<MessageHandler>>,
+}
+
+/// A string passed to us by evcxr. The following need to match the definitions in evcxr.
+#[repr(C)]
+pub struct RawStr {
+    ptr: *const u8,
+    len: usize,
+}
+
+/// A comm message from the frontend, as passed to us by evcxr.
+#[repr(C)]
+pub struct RawCommMessage {
+    /// One of "open", "msg" or "close".
+    message_type: RawStr,
+    comm_id: RawStr,
+    /// Empty unless `message_type` is "open".
+    target_name: RawStr,
+    /// The message's data as JSON.
+    data: RawStr,
+}
+
+impl RawStr {
+    unsafe fn as_str(&self) -> &str {
+        std::str::from_utf8(std::slice::from_raw_parts(self.ptr, self.len)).unwrap_or("")
+    }
+}
+
+/// Called by evcxr, for each loaded cell that uses this crate, with a message from the frontend.
+/// Returns whether we have a handler for the message. For a comm_open, evcxr stops at the first
+/// cell that does, starting from the most recent, and closes the comm if none do.
+///
+/// # Safety
+/// `message` must point to a valid `RawCommMessage` whose strings are valid for the duration of
+/// the call.
+#[no_mangle]
+pub unsafe extern "C" fn evcxr_runtime_comm_dispatch_v1(message: *const RawCommMessage) -> bool {
+    let message = &*message;
+    let message_type = message.message_type.as_str();
+    let comm_id = message.comm_id.as_str();
+    let target_name = message.target_name.as_str();
+    let data = message.data.as_str();
+    // Panics mustn't unwind into evcxr. The panic hook will already have reported the panic.
+    panic::catch_unwind(|| handle_message(message_type, comm_id, target_name, data)).unwrap_or(true)
+}
+
+fn handle_message(message_type: &str, comm_id: &str, target_name: &str, data: &str) -> bool {
+    let comm = Comm {
+        id: comm_id.to_owned(),
+    };
+    match message_type {
+        "open" => {
+            let handler = lock(&REGISTRY).targets.get(target_name).cloned();
+            if let Some(handler) = handler {
+                (lock(&handler))(comm, data);
+                true
+            } else {
+                false
+            }
+        }
+        "msg" => {
+            let handlers = lock(&REGISTRY)
+                .comms
+                .get(comm_id)
+                .map(|handlers| handlers.on_msg.clone());
+            let handled = handlers.is_some();
+            for handler in handlers.unwrap_or_default() {
+                (lock(&handler))(data);
+            }
+            handled
+        }
+        "close" => {
+            let handlers = lock(&REGISTRY).comms.remove(comm_id);
+            let handled = handlers.is_some();
+            for handler in handlers.map(|h| h.on_close).unwrap_or_default() {
+                (lock(&handler))(data);
+            }
+            handled
+        }
+        _ => false,
+    }
+}
+
+/// Sends a message to the kernel as a JSON object with the specified fields, whose values must
+/// already be JSON.
+fn send_to_kernel(fields: &[(&str, String)]) {
+    let fields: Vec<String> = fields
+        .iter()
+        .map(|(name, value)| format!("{}:{}", json_string(name), value))
+        .collect();
+    // If user code printed a partial line, then the message won't be at the start of a line. evcxr
+    // looks for the prefix anywhere in the line to allow for this.
+    println!("{}{{{}}}", COMM_PREFIX, fields.join(","));
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Returns `json` on a single line, so that it can be sent as part of one. Newlines can only appear
+/// in JSON as whitespace, so it's safe to replace them.
+fn single_line(json: &str) -> String {
+    json.replace(['\n', '\r'], " ")
+}
+
+/// Locks `mutex`, ignoring poisoning, since a panic in one handler shouldn't prevent it, or other
+/// handlers, from being called in future.
+fn lock<T: ?Sized>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn new_comm_id() -> String {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    // Each RandomState is seeded differently, so this gives us 128 random-enough bits.
+    (0..2)
+        .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+        .collect()
+}
+
+/// Passes a message to `evcxr_runtime_comm_dispatch_v1` as evcxr would.
+#[cfg(test)]
+pub(crate) fn receive_message(
+    message_type: &str,
+    comm_id: &str,
+    target_name: &str,
+    data: &str,
+) -> bool {
+    fn raw(s: &str) -> RawStr {
+        RawStr {
+            ptr: s.as_ptr(),
+            len: s.len(),
+        }
+    }
+    let message = RawCommMessage {
+        message_type: raw(message_type),
+        comm_id: raw(comm_id),
+        target_name: raw(target_name),
+        data: raw(data),
+    };
+    unsafe { evcxr_runtime_comm_dispatch_v1(&message) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comm_ids_differ() {
+        let id = new_comm_id();
+        assert_eq!(id.len(), 32);
+        assert_ne!(id, new_comm_id());
+    }
+
+    #[test]
+    fn dispatch_to_handlers() {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        {
+            let received = received.clone();
+            register_comm_target("test-target", move |comm, data| {
+                received.lock().unwrap().push(format!("open {}", data));
+                let received = received.clone();
+                comm.on_msg(move |data| received.lock().unwrap().push(format!("msg {}", data)));
+            });
+        }
+        assert!(receive_message("open", "abc", "test-target", r#"{"a":1}"#));
+        assert!(!receive_message("open", "def", "no-such-target", "{}"));
+        assert!(receive_message("msg", "abc", "", "2"));
+        assert!(receive_message("close", "abc", "", "3"));
+        // No longer open, so should be ignored.
+        assert!(!receive_message("msg", "abc", "", "4"));
+        assert_eq!(
+            *received.lock().unwrap(),
+            vec!["open {\"a\":1}".to_owned(), "msg 2".to_owned()]
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(json_string("a\"b\\c\nd\u{1}"), r#""a\"b\\c\nd\u0001""#);
+        assert_eq!(single_line("{\n  \"a\": 1\r\n}"), "{   \"a\": 1  }");
+    }
+}
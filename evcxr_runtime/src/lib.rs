@@ -7,6 +7,15 @@
 
 #[cfg(feature = "bytes")]
 extern crate base64;
+#[cfg(feature = "widgets")]
+#[macro_use]
+extern crate json;
+
+mod comm;
+#[cfg(feature = "widgets")]
+pub mod widgets;
+
+pub use comm::{register_comm_target, Comm};
 
 pub trait Display {
     /// Implementation should emit a representation of itself in one or mime
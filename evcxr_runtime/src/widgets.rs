@@ -0,0 +1,401 @@
+// Copyright 2023 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE
+// or https://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Interactive widgets, implemented on top of the ipywidgets (version 8) comm protocol. Widgets are
+//! only shown by frontends with ipywidgets support, e.g. JupyterLab with the jupyterlab_widgets
+//! package installed. Requires the "widgets" feature.
+//! ```no_run
+//! use evcxr_runtime::widgets::{IntSlider, Label};
+//! let slider = IntSlider::new(0, 100, 50);
+//! let label = Label::new("50");
+//! {
+//!     let label = label.clone();
+//!     slider.on_change(move |value| label.set_value(&value.to_string()));
+//! }
+//! slider.evcxr_display();
+//! label.evcxr_display();
+//! ```
+
+use comm::Comm;
+use json::JsonValue;
+use std::sync::{Arc, Mutex};
+
+const WIDGET_TARGET: &str = "jupyter.widget";
+const PROTOCOL_VERSION: &str = "2.1.0";
+const CONTROLS_MODULE: &str = "@jupyter-widgets/controls";
+const CONTROLS_MODULE_VERSION: &str = "2.0.0";
+const VIEW_MIME_TYPE: &str = "application/vnd.jupyter.widget-view+json";
+
+type Handler = Arc<Mutex<Box<dyn FnMut(&JsonValue) + Send>>>;
+
+/// A widget model whose state is kept in sync with the frontend. Clones refer to the same widget.
+/// Typed wrappers for some common widgets are provided below, but any widget from
+/// @jupyter-widgets/controls can be used via this type.
+#[derive(Clone)]
+pub struct Widget {
+    comm: Comm,
+    shared: Arc<Mutex<WidgetShared>>,
+}
+
+struct WidgetShared {
+    state: JsonValue,
+    change_handlers: Vec<(String, Handler)>,
+    custom_handlers: Vec<Handler>,
+}
+
+impl Widget {
+    /// Creates a widget from @jupyter-widgets/controls. `name` is the name of the model without the
+    /// "Model" suffix, e.g. "IntSlider". `state` should be a JSON object containing any attributes
+    /// that shouldn't take their default values. Panics if `state` isn't valid JSON.
+    pub fn new(name: &str, state: &str) -> Widget {
+        Widget::with_state(name, parse(state))
+    }
+
+    fn with_state(name: &str, mut state: JsonValue) -> Widget {
+        state["_model_module"] = CONTROLS_MODULE.into();
+        state["_model_module_version"] = CONTROLS_MODULE_VERSION.into();
+        state["_model_name"] = format!("{}Model", name).into();
+        state["_view_module"] = CONTROLS_MODULE.into();
+        state["_view_module_version"] = CONTROLS_MODULE_VERSION.into();
+        state["_view_name"] = format!("{}View", name).into();
+        let comm = Comm::open_with_metadata(
+            WIDGET_TARGET,
+            &object! {"state" => state.clone(), "buffer_paths" => array![]}.dump(),
+            &object! {"version" => PROTOCOL_VERSION}.dump(),
+        );
+        let widget = Widget {
+            comm,
+            shared: Arc::new(Mutex::new(WidgetShared {
+                state,
+                change_handlers: Vec::new(),
+                custom_handlers: Vec::new(),
+            })),
+        };
+        let handler_widget = widget.clone();
+        widget.comm.on_msg(move |data| {
+            if let Ok(data) = json::parse(data) {
+                handler_widget.handle_message(&data);
+            }
+        });
+        widget
+    }
+
+    /// Returns the current value of an attribute as JSON.
+    pub fn get(&self, attribute: &str) -> String {
+        self.get_json(attribute).dump()
+    }
+
+    /// Sets an attribute to `value`, which should be JSON, updating the frontend and calling any
+    /// change handlers for the attribute. Panics if `value` isn't valid JSON.
+    pub fn set(&self, attribute: &str, value: &str) {
+        self.apply_change(attribute, parse(value), true);
+    }
+
+    /// Registers a handler to be called with the new value, as JSON, whenever `attribute` changes.
+    pub fn on_change<F: FnMut(&str) + Send + 'static>(&self, attribute: &str, mut handler: F) {
+        self.on_json_change(attribute, move |value| handler(&value.dump()));
+    }
+
+    /// Registers a handler to be called with the content, as JSON, of custom messages from the
+    /// frontend. e.g. button clicks.
+    pub fn on_custom_message<F: FnMut(&str) + Send + 'static>(&self, mut handler: F) {
+        self.on_json_custom_message(move |content| handler(&content.dump()));
+    }
+
+    pub fn comm(&self) -> &Comm {
+        &self.comm
+    }
+
+    /// Displays a view of the widget. A widget can be displayed more than once.
+    pub fn evcxr_display(&self) {
+        let view = object! {
+            "model_id" => self.comm.id(),
+            "version_major" => 2,
+            "version_minor" => 0,
+        };
+        ::mime_type(VIEW_MIME_TYPE).text(view.dump());
+    }
+
+    fn get_json(&self, attribute: &str) -> JsonValue {
+        self.shared.lock().unwrap().state[attribute].clone()
+    }
+
+    fn set_json<T: Into<JsonValue>>(&self, attribute: &str, value: T) {
+        self.apply_change(attribute, value.into(), true);
+    }
+
+    fn on_json_change<F: FnMut(&JsonValue) + Send + 'static>(&self, attribute: &str, handler: F) {
+        let handler: Handler = Arc::new(Mutex::new(Box::new(handler)));
+        self.shared
+            .lock()
+            .unwrap()
+            .change_handlers
+            .push((attribute.to_owned(), handler));
+    }
+
+    fn on_json_custom_message<F: FnMut(&JsonValue) + Send + 'static>(&self, handler: F) {
+        let handler: Handler = Arc::new(Mutex::new(Box::new(handler)));
+        self.shared.lock().unwrap().custom_handlers.push(handler);
+    }
+
+    fn handle_message(&self, data: &JsonValue) {
+        match data["method"].as_str() {
+            Some("update") => {
+                for (attribute, value) in data["state"].entries() {
+                    self.apply_change(attribute, value.clone(), false);
+                }
+            }
+            Some("request_state") => {
+                let state = self.shared.lock().unwrap().state.clone();
+                self.comm.send(
+                    &object! {
+                        "method" => "update",
+                        "state" => state,
+                        "buffer_paths" => array![],
+                    }
+                    .dump(),
+                );
+            }
+            Some("custom") => {
+                let handlers = self.shared.lock().unwrap().custom_handlers.clone();
+                for handler in handlers {
+                    (handler.lock().unwrap())(&data["content"]);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn apply_change(&self, attribute: &str, value: JsonValue, notify_frontend: bool) {
+        let handlers: Vec<Handler> = {
+            let mut shared = self.shared.lock().unwrap();
+            if shared.state[attribute] == value {
+                return;
+            }
+            shared.state[attribute] = value.clone();
+            shared
+                .change_handlers
+                .iter()
+                .filter(|(a, _)| a == attribute)
+                .map(|(_, handler)| handler.clone())
+                .collect()
+        };
+        if notify_frontend {
+            self.comm.send(
+                &object! {
+                    "method" => "update",
+                    "state" => object! {attribute => value.clone()},
+                    "buffer_paths" => array![],
+                }
+                .dump(),
+            );
+        }
+        // Handlers are called without holding our lock, so that they can access the widget.
+        for handler in handlers {
+            (handler.lock().unwrap())(&value);
+        }
+    }
+}
+
+fn parse(json: &str) -> JsonValue {
+    json::parse(json).unwrap_or_else(|error| panic!("Invalid JSON {:?}: {}", json, error))
+}
+
+impl ::Display for Widget {
+    fn evcxr_display(&self) {
+        Widget::evcxr_display(self);
+    }
+}
+
+macro_rules! typed_widget {
+    ($name:ident) => {
+        impl $name {
+            /// Returns the underlying widget, for access to attributes not covered here.
+            pub fn widget(&self) -> &Widget {
+                &self.widget
+            }
+
+            pub fn evcxr_display(&self) {
+                self.widget.evcxr_display();
+            }
+        }
+
+        impl ::Display for $name {
+            fn evcxr_display(&self) {
+                self.widget.evcxr_display();
+            }
+        }
+    };
+}
+
+/// A slider for selecting an integer.
+#[derive(Clone)]
+pub struct IntSlider {
+    widget: Widget,
+}
+
+typed_widget!(IntSlider);
+
+impl IntSlider {
+    pub fn new(min: i64, max: i64, value: i64) -> IntSlider {
+        IntSlider {
+            widget: Widget::with_state(
+                "IntSlider",
+                object! {"min" => min, "max" => max, "value" => value},
+            ),
+        }
+    }
+
+    pub fn value(&self) -> i64 {
+        self.widget.get_json("value").as_i64().unwrap_or_default()
+    }
+
+    pub fn set_value(&self, value: i64) {
+        self.widget.set_json("value", value);
+    }
+
+    /// Registers a handler to be called with the new value whenever the value changes.
+    pub fn on_change<F: FnMut(i64) + Send + 'static>(&self, mut handler: F) {
+        self.widget.on_json_change("value", move |value| {
+            if let Some(value) = value.as_i64() {
+                handler(value);
+            }
+        });
+    }
+}
+
+/// A slider for selecting a floating point number.
+#[derive(Clone)]
+pub struct FloatSlider {
+    widget: Widget,
+}
+
+typed_widget!(FloatSlider);
+
+impl FloatSlider {
+    pub fn new(min: f64, max: f64, value: f64) -> FloatSlider {
+        FloatSlider {
+            widget: Widget::with_state(
+                "FloatSlider",
+                object! {"min" => min, "max" => max, "value" => value, "step" => (max - min) / 100.0},
+            ),
+        }
+    }
+
+    pub fn value(&self) -> f64 {
+        self.widget.get_json("value").as_f64().unwrap_or_default()
+    }
+
+    pub fn set_value(&self, value: f64) {
+        self.widget.set_json("value", value);
+    }
+
+    /// Registers a handler to be called with the new value whenever the value changes.
+    pub fn on_change<F: FnMut(f64) + Send + 'static>(&self, mut handler: F) {
+        self.widget.on_json_change("value", move |value| {
+            if let Some(value) = value.as_f64() {
+                handler(value);
+            }
+        });
+    }
+}
+
+/// A button.
+#[derive(Clone)]
+pub struct Button {
+    widget: Widget,
+}
+
+typed_widget!(Button);
+
+impl Button {
+    pub fn new(description: &str) -> Button {
+        Button {
+            widget: Widget::with_state("Button", object! {"description" => description}),
+        }
+    }
+
+    /// Registers a handler to be called each time the button is clicked.
+    pub fn on_click<F: FnMut() + Send + 'static>(&self, mut handler: F) {
+        self.widget.on_json_custom_message(move |content| {
+            if content["event"] == "click" {
+                handler();
+            }
+        });
+    }
+}
+
+/// A non-editable piece of text.
+#[derive(Clone)]
+pub struct Label {
+    widget: Widget,
+}
+
+typed_widget!(Label);
+
+impl Label {
+    pub fn new(value: &str) -> Label {
+        Label {
+            widget: Widget::with_state("Label", object! {"value" => value}),
+        }
+    }
+
+    pub fn value(&self) -> String {
+        self.widget
+            .get_json("value")
+            .as_str()
+            .unwrap_or("")
+            .to_owned()
+    }
+
+    pub fn set_value(&self, value: &str) {
+        self.widget.set_json("value", value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use comm::receive_message;
+
+    fn message_from_frontend(widget: &Widget, data: JsonValue) {
+        receive_message("msg", widget.comm().id(), "", &data.dump());
+    }
+
+    #[test]
+    fn update_from_frontend() {
+        let slider = IntSlider::new(0, 10, 5);
+        let values = Arc::new(Mutex::new(Vec::new()));
+        {
+            let values = values.clone();
+            slider.on_change(move |value| values.lock().unwrap().push(value));
+        }
+        let update = object! {"method" => "update", "state" => object!{"value" => 7}};
+        message_from_frontend(slider.widget(), update.clone());
+        // Unchanged values don't trigger handlers.
+        message_from_frontend(slider.widget(), update);
+        slider.set_value(3);
+        assert_eq!(*values.lock().unwrap(), vec![7, 3]);
+        assert_eq!(slider.value(), 3);
+    }
+
+    #[test]
+    fn button_click() {
+        let button = Button::new("Go");
+        let clicks = Arc::new(Mutex::new(0));
+        {
+            let clicks = clicks.clone();
+            button.on_click(move || *clicks.lock().unwrap() += 1);
+        }
+        message_from_frontend(
+            button.widget(),
+            object! {"method" => "custom", "content" => object!{"event" => "click"}},
+        );
+        assert_eq!(*clicks.lock().unwrap(), 1);
+        assert_eq!(button.widget().get("_model_name"), "\"ButtonModel\"");
+    }
+}